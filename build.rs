@@ -3,6 +3,60 @@
 use std::env;
 use std::fs;
 use std::path::Path;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+/// Plotly.js release bundled into the binary for headless exports
+const PLOTLY_VERSION: &str = "2.21.0";
+
+/// SHA-256 of the vendored bundle, `https://cdn.plot.ly/plotly-2.21.0.min.js`
+const PLOTLY_SHA256: &str = "";
+
+/// Copies the Plotly.js bundle to `OUT_DIR` so it can be embedded with `include_bytes!`.
+/// The bundle is vendored in `assets` and checked against `PLOTLY_SHA256`, so builds
+/// never need network access. `PYWRY_PLOTLY_JS` points at a custom bundle to use instead.
+fn bundle_plotly() {
+	let out_path = Path::new(&env::var("OUT_DIR").unwrap()).join("plotly.min.js");
+
+	let (bundle_path, pinned) = match env::var("PYWRY_PLOTLY_JS") {
+		Ok(local_path) => (PathBuf::from(local_path), false),
+		Err(_) => {
+			(Path::new("assets").join(format!("plotly-{}.min.js", PLOTLY_VERSION)), true)
+		}
+	};
+	println!("cargo:rerun-if-changed={}", bundle_path.display());
+
+	let bundle = fs::read(&bundle_path).unwrap_or_else(|error| {
+		panic!(
+			"Could not read the Plotly.js bundle {}: {}. Save https://cdn.plot.ly/plotly-{}.min.js there, or set PYWRY_PLOTLY_JS to the path of a local bundle.",
+			bundle_path.display(),
+			error,
+			PLOTLY_VERSION
+		)
+	});
+	let digest = format!("{:x}", Sha256::digest(&bundle));
+	if pinned && PLOTLY_SHA256.is_empty() {
+		panic!(
+			"No checksum is pinned for {}, set PLOTLY_SHA256 in build.rs to its SHA-256: {}",
+			bundle_path.display(),
+			digest
+		);
+	}
+	if pinned && digest != PLOTLY_SHA256 {
+		panic!(
+			"{} has SHA-256 {}, expected {}",
+			bundle_path.display(),
+			digest,
+			PLOTLY_SHA256
+		);
+	}
+
+	// Always written, so switching bundles never leaves a stale one in `OUT_DIR`
+	fs::write(&out_path, bundle).unwrap_or_else(|error| {
+		panic!("Could not write {}: {}", out_path.display(), error)
+	});
+}
 
 fn main() {
 	let out_dir = Path::new("python").join("pywry");
@@ -34,6 +88,9 @@ from .core import PyWry  # noqa: F401"
 		println!("cargo:rustc-cfg=wry_event_loop");
 	}
 
+	bundle_plotly();
+
 	println!("cargo:rerun-if-changed=build.rs");
 	println!("cargo:rerun-if-changed=Cargo.toml");
+	println!("cargo:rerun-if-env-changed=PYWRY_PLOTLY_JS");
 }
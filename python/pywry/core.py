@@ -8,9 +8,9 @@ import threading
 import traceback
 from asyncio.exceptions import CancelledError, IncompleteReadError, TimeoutError
 from pathlib import Path
-from queue import Queue
+from queue import Empty, Full, Queue
 from subprocess import PIPE
-from typing import List, Optional, Union
+from typing import Any, Callable, List, Optional, Union
 
 import setproctitle
 
@@ -23,6 +23,9 @@ if sys.version_info < (3, 9):
 else:
     QueueT = Queue[dict]
 
+# Events kept for `get_event` before the oldest are dropped
+MAX_QUEUED_EVENTS = 1000
+
 AsyncioException = (
     CancelledError,
     IncompleteReadError,
@@ -47,7 +50,18 @@ ACCEPTED_KEYS_TYPES = {
     "height": int,
     "width": int,
     "download_path": (str, Path),
+    "download_conflict": str,
     "export_image": (str, Path),
+    "export_to": str,
+    "id": (str, int),
+    "label": str,
+    "asset_roots": list,
+    "assets": dict,
+    "archive": (str, Path),
+    "mounts": list,
+    "proxy": dict,
+    "navigation": dict,
+    "timeout": (int, float),
 }
 
 
@@ -72,6 +86,8 @@ class PyWry:
     outgoing: List[str] = []
     init_engine: List[str] = []
     recv: QueueT = Queue()
+    events: QueueT = Queue(maxsize=MAX_QUEUED_EVENTS)
+    event_callbacks: List[Callable[[dict], Any]] = []
 
     def __new__(cls, *args, **kwargs):  # pylint: disable=unused-argument
         "Makes the class a 'singleton' by only allowing one instance at a time"
@@ -144,6 +160,44 @@ class PyWry:
         )
         self.send_outgoing(kwargs)
 
+    def send_command(self, cmd: str, window: Union[str, int], **kwargs):
+        """Send a command to a window opened by an earlier message.
+
+        Parameters
+        ----------
+        cmd : str
+            Command to send, e.g. "close", "focus" or "set_title".
+        window : Union[str, int]
+            The handle of the window, sent in its `window_created` event,
+            or the `label` of the message that opened it.
+        **kwargs
+            Arguments of the command, e.g. `title` for "set_title".
+        """
+        self.check_backend()
+        self.outgoing.append(json.dumps(dict(cmd=cmd, window=window, **kwargs)))
+
+    def send_reply(
+        self,
+        window: Union[str, int],
+        call: int,
+        result: Any = None,
+        error: Optional[str] = None,
+    ):
+        """Reply to a `window.pywry.call` made by a page.
+
+        Parameters
+        ----------
+        window : Union[str, int]
+            The `window` of the call event.
+        call : int
+            The `call` of the call event.
+        result : Any, optional
+            Value the promise of the call resolves with, by default None
+        error : Optional[str], optional
+            Message the promise of the call is rejected with, by default None
+        """
+        self.send_command("reply", window=window, call=call, result=result, error=error)
+
     def send_outgoing(self, outgoing: dict):
         """Send outgoing data to backend.
 
@@ -282,12 +336,54 @@ class PyWry:
         """Creates a new task to process messages from the stdout reader."""
         try:
             message: dict = json.loads(data)
-            if message.get("result", None):
+            # Events echo the id of the request of their window, they aren't replies
+            if "event" in message:
+                return self.dispatch_event(message)
+            # Error replies hold a dict, log lines a string
+            is_reply = "id" in message or isinstance(message.get("error"), dict)
+            if message.get("result", None) or is_reply:
                 return self.recv.put(message, block=False)
             self.print_message(message)
         except (json.JSONDecodeError, AttributeError):
             self.clean_print(data)
 
+    def on_event(self, callback: Callable[[dict], Any]):
+        """Calls `callback` with every event of the backend, e.g. `window_closed` or `call`.
+
+        Once a callback is registered, events are no longer put in the `events` queue.
+        Callbacks run on the thread reading the backend, so they shouldn't block.
+        """
+        self.event_callbacks.append(callback)
+
+    def get_event(
+        self, block: bool = True, timeout: Optional[float] = None
+    ) -> Optional[dict]:
+        """Returns the oldest event of the backend, or None if none came in time."""
+        try:
+            return self.events.get(block=block, timeout=timeout)
+        except Empty:
+            return None
+
+    def dispatch_event(self, message: dict):
+        """Passes an event to the registered callbacks, or queues it for `get_event`."""
+        if self.event_callbacks:
+            for callback in self.event_callbacks:
+                try:
+                    callback(message)
+                except Exception:  # pylint: disable=broad-except
+                    traceback.print_exc()
+            return
+
+        # Drop the oldest event, so events nobody reads don't pile up
+        while True:
+            try:
+                return self.events.put(message, block=False)
+            except Full:
+                try:
+                    self.events.get_nowait()
+                except Empty:
+                    pass
+
     async def stdout_reader(self):
         """Read stdout from the backend."""
         try:
@@ -383,7 +479,7 @@ class PyWry:
         """Run the backend."""
         asyncio.run(self.run_backend())
 
-    def start(self, debug: bool = False, headless: bool = False):
+    def start(self, debug: bool = False, headless: bool = False, renderers: int = 1):
         """Creates a new thread and runs the backend in it.
 
         Parameters
@@ -393,6 +489,8 @@ class PyWry:
         headless : bool, optional
             Whether to run the backend in headless mode for plotly image exports,
             by default False
+        renderers : int, optional
+            Number of headless webviews rendering exports at the same time, by default 1
         """
         if self._is_started.is_set():
             return
@@ -402,6 +500,8 @@ class PyWry:
         for arg, flag in zip([debug, headless], ["debug", "headless"]):
             if arg:
                 self._bootargs.append(f"--{flag}")
+        if headless and renderers > 1:
+            self._bootargs.extend(["--renderers", str(renderers)])
 
         thread = threading.Thread(target=self.run, daemon=self.daemon)
         thread.start()
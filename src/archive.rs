@@ -0,0 +1,285 @@
+use flate2::read::GzDecoder;
+use std::{
+	collections::HashMap,
+	fs::File,
+	io::Read,
+	path::{Component, Path},
+};
+use tar::Archive;
+use zip::ZipArchive;
+
+/// Largest uncompressed size of a file of an archive.
+pub const MAX_ENTRY_SIZE: u64 = 32 * 1024 * 1024;
+
+/// Largest uncompressed size of all the files of an archive together. Archives are read
+/// on the event loop thread when their window is created, so this bounds how long the
+/// other windows wait on it.
+pub const MAX_ARCHIVE_SIZE: u64 = 64 * 1024 * 1024;
+
+const LIMITS: (u64, u64) = (MAX_ENTRY_SIZE, MAX_ARCHIVE_SIZE);
+
+/// Reads every file of a `.zip`, `.tar.gz` or `.tgz` archive into memory, so a window
+/// can serve it as its web root without extracting it
+/// # Arguments
+/// * `path` - The path to the archive
+/// # Returns
+/// * `Result<HashMap<String, Vec<u8>>, String>` - The files of the archive by path, or why it can't be read
+pub fn read_archive(path: &str) -> Result<HashMap<String, Vec<u8>>, String> {
+	let file = File::open(path)
+		.map_err(|error| format!("archive not found: {}: {}", path, error))?;
+	let name = path.to_lowercase();
+	let files = if name.ends_with(".zip") {
+		read_zip(file)
+	} else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+		read_tar_gz(file)
+	} else {
+		return Err(format!(
+			"Unsupported archive {}, expected a .zip, .tar.gz or .tgz file",
+			path
+		));
+	};
+	files
+		.map(strip_top_directory)
+		.map_err(|error| format!("Invalid archive {}: {}", path, error))
+}
+
+/// Reads a file of an archive, stopping as soon as it is larger than the size limits allow
+/// # Arguments
+/// * `entry` - The reader of the file
+/// * `path` - The path of the file in the archive
+/// * `total` - The size of the files read so far, which the file is added to
+/// * `limits` - The largest size of a file, and of all the files together
+/// # Returns
+/// * `Result<Vec<u8>, String>` - The contents of the file, or why it can't be read
+fn read_entry(
+	entry: impl Read, path: &str, total: &mut u64, limits: (u64, u64),
+) -> Result<Vec<u8>, String> {
+	let (max_entry, max_archive) = limits;
+	let limit = max_entry.min(max_archive.saturating_sub(*total));
+	let mut data = Vec::new();
+	entry.take(limit + 1).read_to_end(&mut data).map_err(|error| error.to_string())?;
+	let size = data.len() as u64;
+	if size > max_entry {
+		return Err(format!("{} is larger than {} bytes uncompressed", path, max_entry));
+	}
+	if size > limit {
+		return Err(format!(
+			"its files are larger than {} bytes uncompressed",
+			max_archive
+		));
+	}
+	*total += size;
+	Ok(data)
+}
+
+/// Returns the path of an archive entry as served over `wry://`, without `.` or `..` components
+fn entry_path(path: &Path) -> String {
+	let components: Vec<_> = path
+		.components()
+		.filter_map(|component| match component {
+			Component::Normal(name) => Some(name.to_string_lossy()),
+			_ => None,
+		})
+		.collect();
+	components.join("/")
+}
+
+fn read_zip(file: File) -> Result<HashMap<String, Vec<u8>>, String> {
+	let mut archive = ZipArchive::new(file).map_err(|error| error.to_string())?;
+	let mut files = HashMap::new();
+	let mut total = 0;
+	for index in 0..archive.len() {
+		let mut entry = archive.by_index(index).map_err(|error| error.to_string())?;
+		// Entries whose path would leave the archive, like `../secret`, have no enclosed name
+		let path = match entry.enclosed_name() {
+			Some(path) if !entry.is_dir() => entry_path(path),
+			_ => continue,
+		};
+		let data = read_entry(&mut entry, &path, &mut total, LIMITS)?;
+		files.insert(path, data);
+	}
+	Ok(files)
+}
+
+fn read_tar_gz(file: File) -> Result<HashMap<String, Vec<u8>>, String> {
+	let mut archive = Archive::new(GzDecoder::new(file));
+	let mut files = HashMap::new();
+	let mut total = 0;
+	for entry in archive.entries().map_err(|error| error.to_string())? {
+		let mut entry = entry.map_err(|error| error.to_string())?;
+		if !entry.header().entry_type().is_file() {
+			continue;
+		}
+		let path = entry_path(&entry.path().map_err(|error| error.to_string())?);
+		let data = read_entry(&mut entry, &path, &mut total, LIMITS)?;
+		files.insert(path, data);
+	}
+	Ok(files)
+}
+
+/// Drops the directory archives often wrap their files in, e.g. `dist/`,
+/// so `index.html` ends up at the web root
+fn strip_top_directory(files: HashMap<String, Vec<u8>>) -> HashMap<String, Vec<u8>> {
+	if files.contains_key("index.html") {
+		return files;
+	}
+	let top = files
+		.keys()
+		.next()
+		.and_then(|path| path.split_once('/'))
+		.map(|(directory, _)| format!("{}/", directory));
+	match top {
+		Some(top) if files.keys().all(|path| path.starts_with(&top)) => files
+			.into_iter()
+			.map(|(path, data)| (path[top.len()..].to_string(), data))
+			.collect(),
+		_ => files,
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use flate2::{Compression, write::GzEncoder};
+	use std::{env, io::Write, path::PathBuf};
+	use tar::{Builder, EntryType, Header};
+	use zip::{ZipWriter, write::FileOptions};
+
+	fn archive_path(name: &str) -> PathBuf {
+		env::temp_dir().join(format!("pywry-{}-{}", std::process::id(), name))
+	}
+
+	fn write_zip(name: &str, files: &[(&str, &str)]) -> String {
+		let path = archive_path(name);
+		let mut zip = ZipWriter::new(File::create(&path).unwrap());
+		for (name, text) in files {
+			zip.start_file(*name, FileOptions::default()).unwrap();
+			zip.write_all(text.as_bytes()).unwrap();
+		}
+		zip.finish().unwrap();
+		path.to_string_lossy().to_string()
+	}
+
+	/// Writes a `.tar.gz` archive, with names written as is so they can leave the archive
+	fn write_tar_gz(name: &str, files: &[(&str, &str)]) -> String {
+		let path = archive_path(name);
+		let mut tar =
+			Builder::new(GzEncoder::new(File::create(&path).unwrap(), Compression::fast()));
+		for (name, text) in files {
+			let mut header = Header::new_gnu();
+			header.as_gnu_mut().unwrap().name[..name.len()].copy_from_slice(name.as_bytes());
+			let directory = name.ends_with('/');
+			header.set_entry_type(if directory {
+				EntryType::Directory
+			} else {
+				EntryType::Regular
+			});
+			header.set_size(text.len() as u64);
+			header.set_mode(0o644);
+			header.set_cksum();
+			tar.append(&header, text.as_bytes()).unwrap();
+		}
+		tar.into_inner().unwrap().finish().unwrap();
+		path.to_string_lossy().to_string()
+	}
+
+	fn sorted(files: HashMap<String, Vec<u8>>) -> Vec<(String, String)> {
+		let mut files: Vec<_> = files
+			.into_iter()
+			.map(|(path, data)| (path, String::from_utf8(data).unwrap()))
+			.collect();
+		files.sort();
+		files
+	}
+
+	fn owned(files: &[(&str, &str)]) -> Vec<(String, String)> {
+		files.iter().map(|(path, text)| (path.to_string(), text.to_string())).collect()
+	}
+
+	#[test]
+	fn zip_files_are_served_from_their_top_directory() {
+		let path = write_zip(
+			"site.zip",
+			&[
+				("dist/index.html", "index"),
+				("dist/js/app.js", "app"),
+				("../evil.txt", "evil"),
+			],
+		);
+		let files = sorted(read_archive(&path).unwrap());
+		assert_eq!(files, owned(&[("index.html", "index"), ("js/app.js", "app")]));
+	}
+
+	#[test]
+	fn tar_gz_entries_stay_inside_the_archive() {
+		let path = write_tar_gz(
+			"site.tgz",
+			&[
+				("app/", ""),
+				("app/index.html", "index"),
+				("../evil.txt", "evil"),
+				("/etc/passwd", "root"),
+				("app/./js/../app.js", "app"),
+			],
+		);
+		let files = sorted(read_archive(&path).unwrap());
+		// `..` components are dropped rather than resolved, so nothing can climb out
+		let expected = [
+			("app/index.html", "index"),
+			("app/js/app.js", "app"),
+			("etc/passwd", "root"),
+			("evil.txt", "evil"),
+		];
+		assert_eq!(files, owned(&expected));
+		assert_eq!(entry_path(Path::new("../a/./b")), "a/b");
+	}
+
+	#[test]
+	fn strip_top_directory_needs_a_shared_directory() {
+		let files = |paths: &[&str]| -> HashMap<String, Vec<u8>> {
+			paths.iter().map(|path| (path.to_string(), Vec::new())).collect()
+		};
+		let keys = |files: HashMap<String, Vec<u8>>| {
+			let mut keys: Vec<String> = files.into_keys().collect();
+			keys.sort();
+			keys
+		};
+		assert_eq!(
+			keys(strip_top_directory(files(&["dist/index.html", "dist/a.js"]))),
+			["a.js", "index.html"]
+		);
+		assert_eq!(
+			keys(strip_top_directory(files(&["index.html", "dist/a.js"]))),
+			["dist/a.js", "index.html"]
+		);
+		assert_eq!(
+			keys(strip_top_directory(files(&["a/index.html", "b/a.js"]))),
+			["a/index.html", "b/a.js"]
+		);
+	}
+
+	#[test]
+	fn read_entry_enforces_the_size_limits() {
+		let mut total = 0;
+		assert_eq!(read_entry(&b"1234"[..], "a", &mut total, (4, 10)).unwrap(), b"1234");
+		assert_eq!(total, 4);
+		let error = read_entry(&b"12345"[..], "b", &mut total, (4, 10)).unwrap_err();
+		assert_eq!(error, "b is larger than 4 bytes uncompressed");
+		assert!(read_entry(&b"1234"[..], "c", &mut total, (4, 10)).is_ok());
+		let error = read_entry(&b"123"[..], "d", &mut total, (4, 10)).unwrap_err();
+		assert_eq!(error, "its files are larger than 10 bytes uncompressed");
+	}
+
+	#[test]
+	fn other_files_are_refused() {
+		assert!(read_archive("missing.zip").unwrap_err().starts_with("archive not found"));
+		let path = archive_path("site.rar");
+		std::fs::write(&path, "rar").unwrap();
+		let error = read_archive(&path.to_string_lossy()).unwrap_err();
+		assert!(error.starts_with("Unsupported archive"));
+		let path = archive_path("broken.zip");
+		std::fs::write(&path, "not a zip").unwrap();
+		let error = read_archive(&path.to_string_lossy()).unwrap_err();
+		assert!(error.starts_with("Invalid archive"));
+	}
+}
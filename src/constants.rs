@@ -95,9 +95,43 @@ pub const PYWRY_WINDOW_SCRIPT: &str = "
 		devtools: function () {
 			window.ipc.postMessage('#DEVTOOLS');
 		},
+		// Calls `name` on the Python side, the returned promise settles with its reply
+		call: function (name, args) {
+			const call = ++window.pywry._last_call;
+			return new Promise(function (resolve, reject) {
+				window.pywry._calls[call] = { resolve: resolve, reject: reject };
+				const message = { call: call, name: name, args: args === undefined ? null : args };
+				window.ipc.postMessage(`#PYWRY_CALL:${JSON.stringify(message)}`);
+			});
+		},
+		_settle: function (call, result, error) {
+			const pending = window.pywry._calls[call];
+			if (!pending) {
+				return false;
+			}
+			delete window.pywry._calls[call];
+			if (error != null) {
+				pending.reject(new Error(error));
+			} else {
+				pending.resolve(result);
+			}
+			return true;
+		},
+		_calls: {},
+		_last_call: 0,
 	};
 ";
 
+/// Added to the html pages of a proxied server, so pages in iframes share the
+/// `window.pywry` of the window.
+pub const PROXY_INJECT_JS: &str = "
+	if (!window.pywry) {
+		try {
+			window.pywry = window.top.pywry;
+		} catch (error) {}
+	}
+";
+
 // Add keyboard shortcuts for copy and paste (fixes Mac OS)
 pub const MACOS_COPY_PASTE_SCRIPT: &str = "
 	try {
@@ -116,14 +150,37 @@ pub const MACOS_COPY_PASTE_SCRIPT: &str = "
 	}
 ";
 
+/// Plotly.js bundle embedded at build time, so headless exports work offline.
+pub const PLOTLY_JS: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/plotly.min.js"));
+
+/// Path the headless renderers load the Plotly.js bundle from, a macro so it can be
+/// used in `concat!`.
+macro_rules! plotly_js_path {
+	() => {
+		"/_pywry/plotly.min.js"
+	};
+}
+
+/// Path the headless renderers load the Plotly.js bundle from.
+pub const PLOTLY_JS_PATH: &str = plotly_js_path!();
+
+/// URL the headless renderers are loaded from.
+#[cfg(target_os = "windows")]
+pub const HEADLESS_URL: &str = "https://wry.localhost";
+#[cfg(not(target_os = "windows"))]
+pub const HEADLESS_URL: &str = "wry://localhost";
+
 /// Plotly script that is injected into the HTML to render the plot
 /// headless and then send the image back to the Python backend.
-pub const HEADLESS_HTML: &str = "
+pub const HEADLESS_HTML: &str = concat!(
+	"
 <html>
 	<head>
 		<meta charset='utf-8' />
 		<meta name='viewport' content='width=device-width, initial-scale=1' />
-		<script src='https://cdn.plot.ly/plotly-2.21.0.min.js'></script>
+		<script src='",
+	plotly_js_path!(),
+	"'></script>
 		<style>
 			html,
 			body {
@@ -134,11 +191,24 @@ pub const HEADLESS_HTML: &str = "
 		</style>
 	</head>
 	<body>
+		<script>
+			window.addEventListener('load', () => window.ipc.postMessage('#RENDERER_READY'));
+		</script>
 	</body>
 </html>
-";
+"
+);
 
 pub const PLOTLY_RENDER_JS: &str = "
+function plotly_render_error(code, err) {
+	const error = {
+		code: code,
+		message: String((err && err.message) || err),
+		stack: (err && err.stack) || null,
+	};
+	window.ipc.postMessage(`#RENDER_ERROR:${JSON.stringify(error)}`);
+}
+
 function plotly_render(info) {
 	const opts = {};
 	try {
@@ -159,17 +229,279 @@ function plotly_render(info) {
 		opts.figure = { ...figure, config: config };
 		opts.imgOpts = imgOpts;
 	} catch (err) {
-		return window.pywry.result(err);
+		return plotly_render_error('invalid_figure', err);
 	}
 	try {
-		Plotly.toImage(opts.figure, opts.imgOpts).then(function (
-			imageData
-		) {
-			return window.pywry.result(imageData);
-		});
+		Plotly.toImage(opts.figure, opts.imgOpts)
+			.then(function (imageData) {
+				return window.pywry.result(imageData);
+			})
+			.catch(function (err_2) {
+				return plotly_render_error('render_failed', err_2);
+			});
 	} catch (err_1) {
-		return window.pywry.result(err_1);
+		return plotly_render_error('render_failed', err_1);
 	}
 	return true;
 }
 ";
+
+/// Script that is injected into the HTML to update the content of a window in place,
+/// called with the `html`, `json_data` and `figure` of an `update` command, or the
+/// points of `stream` commands.
+pub const UPDATE_JS: &str = "
+function pywry_figure() {
+	const gd = document.querySelector('.js-plotly-plot');
+	if (!gd || !window.Plotly) {
+		console.error('pywry: the page has no Plotly figure to update');
+		return null;
+	}
+	return gd;
+}
+
+function pywry_restore_scroll(x, y) {
+	window.scrollTo(x, y);
+	window.addEventListener('load', () => window.scrollTo(x, y), { once: true });
+}
+
+async function pywry_react(figure) {
+	const gd = pywry_figure();
+	if (!gd) {
+		return;
+	}
+	// Keep the zoom and pan of the user unless the figure sets its own uirevision
+	const layout = figure.layout || {};
+	if (layout.uirevision === undefined) {
+		if (gd.layout.uirevision === undefined) {
+			await Plotly.relayout(gd, { uirevision: 'pywry' });
+		}
+		layout.uirevision = gd.layout.uirevision;
+	}
+	return Plotly.react(gd, figure.data || [], layout, figure.config);
+}
+
+function pywry_update(update) {
+	const x = window.scrollX;
+	const y = window.scrollY;
+	if (update.json_data != null) {
+		window.json_data = update.json_data;
+	}
+	if (update.html != null) {
+		document.open();
+		document.write(update.html);
+		document.close();
+		pywry_restore_scroll(x, y);
+	}
+	if (update.figure != null) {
+		pywry_react(update.figure).catch((err) => console.error(err));
+	}
+	window.dispatchEvent(new CustomEvent('pywry:update', { detail: update }));
+}
+
+function pywry_extend(update, indices, maxPoints) {
+	const gd = pywry_figure();
+	if (gd) {
+		Plotly.extendTraces(gd, update, indices, maxPoints ?? undefined);
+	}
+}
+";
+
+/// Wraps the script of an `eval` command, posting the value of its last expression,
+/// or the error it throws, back to the IPC handler. `{eval}` is replaced by the id
+/// of the eval and `{script}` by the script as a JSON string.
+pub const EVAL_JS: &str = "
+(async () => {
+	const post = (outcome) => window.ipc.postMessage(`#EVAL_RESULT:${JSON.stringify(outcome)}`);
+	try {
+		const result = await (0, eval)({script});
+		post({ eval: {eval}, result: result === undefined ? null : result });
+	} catch (err) {
+		post({
+			eval: {eval},
+			error: {
+				code: 'eval_failed',
+				message: String((err && err.message) || err),
+				stack: (err && err.stack) || null,
+			},
+		});
+	}
+})();
+";
+
+/// Forwards the Plotly events listed in `window.pywry_plotly_events` of every figure
+/// in the page to the IPC handler. Figures are picked up as Plotly draws them, so ones
+/// drawn later or after an `update` replaced the page get their events too.
+pub const PLOTLY_EVENTS_JS: &str = "
+(() => {
+	const HOVER_THROTTLE_MS = 100;
+	const events = window.pywry_plotly_events || [];
+	let lastHover = 0;
+	let pendingHover = null;
+
+	// Event data holds references to the figure, only the plain values are sent
+	const points = (data) =>
+		((data && data.points) || []).map((pt) => ({
+			curveNumber: pt.curveNumber,
+			pointNumber: pt.pointNumber,
+			pointNumbers: pt.pointNumbers,
+			x: pt.x,
+			y: pt.y,
+			z: pt.z,
+			text: pt.text,
+			customdata: pt.customdata,
+		}));
+	const serialize = {
+		click: (data) => ({ points: points(data) }),
+		hover: (data) => ({ points: points(data) }),
+		selected: (data) => ({
+			points: points(data),
+			range: data && data.range,
+			lassoPoints: data && data.lassoPoints,
+		}),
+		relayout: (data) => data,
+	};
+
+	const post = (name, data) => {
+		const message = { name: name, data: data || null };
+		window.ipc.postMessage(`#PLOTLY_EVENT:${JSON.stringify(message)}`);
+	};
+
+	// Hovers within the throttle are held back, the latest one is sent once it ends
+	const hover = (data) => {
+		const wait = lastHover + HOVER_THROTTLE_MS - Date.now();
+		if (wait <= 0) {
+			lastHover = Date.now();
+			return post('hover', data);
+		}
+		if (pendingHover === null) {
+			setTimeout(() => {
+				lastHover = Date.now();
+				post('hover', pendingHover);
+				pendingHover = null;
+			}, wait);
+		}
+		pendingHover = data;
+	};
+
+	const send = (name, data) => {
+		// Serialized right away, the figure may change before a held back hover is sent
+		const serialized = serialize[name](data);
+		return name === 'hover' ? hover(serialized) : post(name, serialized);
+	};
+
+	const attach = () => {
+		document.querySelectorAll('.js-plotly-plot').forEach((gd) => {
+			if (gd._pywry_events || typeof gd.on !== 'function') {
+				return;
+			}
+			gd._pywry_events = true;
+			events.forEach((name) => gd.on(`plotly_${name}`, (data) => send(name, data)));
+		});
+	};
+
+	// Plotly adds the class to the element of a figure when drawing it, once it takes listeners
+	new MutationObserver(attach).observe(document, {
+		subtree: true,
+		childList: true,
+		attributes: true,
+		attributeFilter: ['class'],
+	});
+	attach();
+})();
+";
+
+/// Forwards the console messages of the page from `window.pywry_console_level` up,
+/// uncaught errors and unhandled promise rejections to the IPC handler.
+/// The original console methods are still called, so devtools keep working.
+pub const FORWARD_CONSOLE_JS: &str = r"
+(() => {
+	const LEVELS = ['log', 'info', 'warn', 'error'];
+	const lowest = Math.max(LEVELS.indexOf(window.pywry_console_level), 0);
+
+	const post = (level, message, source, line, column) => {
+		const record = {
+			level: level,
+			message: message,
+			source: source ?? null,
+			line: line ?? null,
+			column: column ?? null,
+		};
+		window.ipc.postMessage(`#CONSOLE:${JSON.stringify(record)}`);
+	};
+
+	const format = (arg) => {
+		if (arg instanceof Error) {
+			return arg.stack || String(arg);
+		}
+		if (typeof arg === 'string') {
+			return arg;
+		}
+		try {
+			return JSON.stringify(arg);
+		} catch (_) {
+			return String(arg);
+		}
+	};
+
+	LEVELS.slice(lowest).forEach((level) => {
+		const original = console[level].bind(console);
+		console[level] = (...args) => {
+			post(level, args.map(format).join(' '));
+			original(...args);
+		};
+	});
+
+	window.addEventListener('error', (event) => {
+		const message = event.error ? format(event.error) : event.message;
+		post('error', message, event.filename, event.lineno, event.colno);
+	});
+	window.addEventListener('unhandledrejection', (event) => {
+		post('error', `Unhandled promise rejection: ${format(event.reason)}`);
+	});
+})();
+";
+
+/// Sends the blob `{uri}` points at to the IPC handler as base64 data URLs of up to 1MB,
+/// using the `URL.getFromObjectURL` hook of `BLOBINIT_SCRIPT`. Every message starts with
+/// `#BLOB_<STEP>:{download}:`, the id of the download. Blobs are sent one after the other,
+/// in the order their downloads started, and stop once the download is cancelled.
+pub const BLOB_DOWNLOAD_JS: &str = "
+(() => {
+	const CHUNK_SIZE = 1024 * 1024;
+	const uri = {uri};
+	const download = {download};
+	const post = (step, payload) => window.ipc.postMessage(`#BLOB_${step}:${download}:${payload}`);
+	window.pywry_cancelled_downloads = window.pywry_cancelled_downloads || new Set();
+	const read = (blob) =>
+		new Promise((resolve, reject) => {
+			const reader = new FileReader();
+			reader.onload = () => resolve(reader.result);
+			reader.onerror = () => reject(reader.error);
+			reader.readAsDataURL(blob);
+		});
+
+	const previous = window.pywry_blob_downloads || Promise.resolve();
+	window.pywry_blob_downloads = previous.then(async () => {
+		try {
+			const blob = URL.getFromObjectURL(uri) || (await (await fetch(uri)).blob());
+			post('START', blob.size);
+			for (let start = 0; start < blob.size; start += CHUNK_SIZE) {
+				const chunk = await read(blob.slice(start, start + CHUNK_SIZE));
+				if (window.pywry_cancelled_downloads.has(download)) {
+					return;
+				}
+				post('CHUNK', chunk);
+			}
+			post('END', '');
+		} catch (err) {
+			post('ERROR', (err && err.message) || err);
+		}
+	});
+})();
+";
+
+/// Stops sending the blob of a cancelled download, `{download}` is its id.
+pub const CANCEL_BLOB_JS: &str = "
+window.pywry_cancelled_downloads = window.pywry_cancelled_downloads || new Set();
+window.pywry_cancelled_downloads.add({download});
+";
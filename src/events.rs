@@ -1,121 +1,891 @@
-use crate::structs::{ConsolePrinter, PlotData, Showable, UserEvent};
+use crate::constants::{DEV_TOOLS_HTML, EVAL_JS, HEADLESS_URL};
+use crate::handlers::add_handlers;
+use crate::protocol::{
+	AckReply, ClientEvent, ConflictPolicy, ConsoleRecord, ErrorInfo, ErrorReply,
+	EvalOutcome, ExportTarget, PageCall, PlotlyEventMessage, ResultReply, WindowCommand,
+	WindowGeometry, WindowHandle,
+};
+use crate::stream::StreamFrame;
+use crate::structs::{
+	ConsolePrinter, EVAL_TIMEOUT, GEOMETRY_EVENT_INTERVAL, GeometryEvents, PendingEval,
+	PlotData, RenderQueue, RenderStatus, Showable, ShowableHeadless, UserEvent,
+	WindowState, read_html,
+};
 use crate::window::create_new_window;
 
 #[cfg(not(target_os = "macos"))]
-use crate::utils::decode_path;
+use crate::{
+	constants::BLOB_DOWNLOAD_JS,
+	utils::{decode_path, download_target},
+};
+use crate::{
+	constants::CANCEL_BLOB_JS,
+	structs::{BlobDownload, Download},
+	utils::{export_file_name, file_digest, get_icon, resolve_conflict},
+};
 
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use serde_json::{Value, json};
 use std::{
 	collections::HashMap,
-	io::{self, Write},
-	path::PathBuf,
+	path::{Path, PathBuf},
+	thread,
+	time::{Duration, Instant},
 };
 
 #[cfg(not(target_os = "macos"))]
-use std::fs::{copy, create_dir_all, remove_file};
+use std::fs::copy;
+use std::fs::{create_dir_all, read, remove_file, write};
 use urlencoding::decode as urldecode;
 
-use wry::{
-	application::{
-		event::{Event, WindowEvent},
-		event_loop::{EventLoopProxy, EventLoopWindowTarget},
-		window::WindowId,
-	},
-	webview::WebView,
+use wry::application::{
+	dpi::{LogicalPosition, LogicalSize},
+	event::{Event, StartCause, WindowEvent},
+	event_loop::{EventLoopProxy, EventLoopWindowTarget},
+	window::{Fullscreen, Window, WindowId},
 };
 
-#[cfg(not(target_os = "windows"))]
 use wry::{
-	application::{
-		dpi::LogicalSize,
-		window::{Theme, WindowBuilder},
-	},
+	application::window::{Theme, WindowBuilder},
 	webview::WebViewBuilder,
 };
 
+#[cfg(target_os = "windows")]
+use crate::utils::web_context;
+
+/// Removes a window from the event loop and tells the client it was closed
+fn close_window(
+	webviews: &mut HashMap<WindowId, WindowState>, window_id: WindowId,
+	console: ConsolePrinter,
+) {
+	match webviews.remove(&window_id) {
+		Some(state) => {
+			console.debug("Closing Webview");
+			for eval in state.evals.values() {
+				console.send_error(
+					"window_closed",
+					"The window was closed before the script finished",
+					eval.request_id.as_ref(),
+				);
+			}
+			console.send(
+				ClientEvent::WindowClosed { window: state.handle },
+				state.request_id.as_ref(),
+			);
+		}
+		None => console.debug("Webview not found"),
+	}
+}
+
+/// Returns the position and size of a window, in logical pixels
+fn window_geometry(window: &Window) -> WindowGeometry {
+	let scale_factor = window.scale_factor();
+	let position = window.outer_position().unwrap_or_default().to_logical(scale_factor);
+	let size = window.inner_size().to_logical(scale_factor);
+	WindowGeometry {
+		x: position.x,
+		y: position.y,
+		width: size.width,
+		height: size.height,
+	}
+}
+
+/// Sends the resize and move events a window held back, with its current geometry
+fn flush_geometry_events(state: &mut WindowState, console: ConsolePrinter) {
+	let geometry_events = &mut state.geometry_events;
+	let (resized, moved) = (geometry_events.resized, geometry_events.moved);
+	if !resized && !moved {
+		return;
+	}
+	*geometry_events =
+		GeometryEvents { last_sent: Some(Instant::now()), ..Default::default() };
+	let geometry = window_geometry(state.webview.window());
+	let window = state.handle.clone();
+	if resized {
+		let event =
+			ClientEvent::WindowResized { window: window.clone(), geometry: geometry.clone() };
+		console.send(event, None);
+	}
+	if moved {
+		console.send(ClientEvent::WindowMoved { window, geometry }, None);
+	}
+}
+
+/// Sends the resize and move events of every window that are due
+fn flush_due_geometry_events(
+	webviews: &mut HashMap<WindowId, WindowState>, console: ConsolePrinter,
+) {
+	let now = Instant::now();
+	for state in webviews.values_mut() {
+		if state.geometry_events.deadline().map_or(false, |deadline| deadline <= now) {
+			flush_geometry_events(state, console.clone());
+		}
+	}
+}
+
+/// Tells the client a window was resized, moved, focused or blurred. Resize and move events
+/// are sent at most once every `GEOMETRY_EVENT_INTERVAL`, the latest one after the interval.
+fn send_window_event(
+	event: &WindowEvent, state: &mut WindowState, console: ConsolePrinter,
+) {
+	let window = state.handle.clone();
+	let event = match event {
+		WindowEvent::Resized(_) | WindowEvent::Moved(_) => {
+			let geometry_events = &mut state.geometry_events;
+			match event {
+				WindowEvent::Resized(_) => geometry_events.resized = true,
+				_ => geometry_events.moved = true,
+			}
+			let is_due = geometry_events
+				.last_sent
+				.map_or(true, |last_sent| last_sent.elapsed() >= GEOMETRY_EVENT_INTERVAL);
+			if is_due {
+				flush_geometry_events(state, console);
+			}
+			return;
+		}
+		WindowEvent::Focused(true) => ClientEvent::WindowFocused { window },
+		WindowEvent::Focused(false) => ClientEvent::WindowBlurred { window },
+		_ => return,
+	};
+	console.send(event, None);
+}
+
+/// Runs the script of an `eval` command in a window, it is replied to once the script finishes
+fn run_eval(
+	state: &mut WindowState, script: &str, timeout: Option<f64>,
+	request_id: Option<Value>, console: ConsolePrinter,
+) {
+	state.last_eval += 1;
+	let eval = state.last_eval;
+	let script = EVAL_JS
+		.replace("{eval}", &eval.to_string())
+		.replace("{script}", &json!(script).to_string());
+	if let Err(error) = state.webview.evaluate_script(&script) {
+		return console.send_error("window_error", &error.to_string(), request_id.as_ref());
+	}
+	let deadline = Instant::now() + timeout.map_or(EVAL_TIMEOUT, Duration::from_secs_f64);
+	state.evals.insert(eval, PendingEval { request_id, deadline });
+}
+
+/// Removes the first download of a window `matches` returns true for
+fn take_download(
+	state: &mut WindowState, matches: impl Fn(&Download) -> bool,
+) -> Option<Download> {
+	let index = state.downloads.iter().position(matches)?;
+	Some(state.downloads.remove(index))
+}
+
+/// Saves a download and hashes it on a worker thread, so big files don't block the windows.
+/// The event loop gets the outcome as a `DownloadFinished` event.
+/// # Arguments
+/// * `download` - The id of the download
+/// * `save` - Moves or writes the file where it belongs, returning its path
+/// * `close_window` - Whether the window closes once the download is saved
+/// * `window_id` - The window the download belongs to
+/// * `proxy` - The proxy of the event loop
+fn finish_download(
+	download: u64, save: impl FnOnce() -> Result<PathBuf, String> + Send + 'static,
+	close_window: bool, window_id: WindowId, proxy: &EventLoopProxy<UserEvent>,
+) {
+	let proxy = proxy.clone();
+	thread::spawn(move || {
+		let outcome = save().and_then(|path| match file_digest(&path) {
+			Ok((size, sha256)) => Ok((path, size, sha256)),
+			Err(error) => Err(format!("Error reading {:?}: {}", path, error)),
+		});
+		let event = UserEvent::DownloadFinished(download, outcome, close_window, window_id);
+		proxy.send_event(event).unwrap_or_default();
+	});
+}
+
+/// Replies to the request that opened an export window with the exported file,
+/// base64 encoded, instead of saving it
+fn send_export(
+	download: u64, path: &Path, data: &[u8], console: ConsolePrinter,
+	request_id: Option<&Value>,
+) {
+	let mime_type = mime_guess::from_path(path).first_or_octet_stream().to_string();
+	let result = json!({
+		"filename": export_file_name(download, path),
+		"mime_type": mime_type,
+		"data": BASE64.encode(data),
+	});
+	console.send(ResultReply { result }, request_id);
+}
+
+/// Reads back the temporary file an export kept in memory was saved to, and replies with it
+fn send_export_file(
+	download: u64, path: &Path, console: ConsolePrinter, request_id: Option<&Value>,
+) {
+	let data = read(path);
+	if let Err(error) = remove_file(path) {
+		console.error(&format!("Error deleting file: {}", error));
+	}
+	match data {
+		Err(error) => {
+			let message = format!("Error reading {:?}: {}", path, error);
+			console.send_error("download_failed", &message, request_id);
+		}
+		Ok(data) => send_export(download, path, &data, console, request_id),
+	}
+}
+
+/// Moves a file saved by the webview to where the window saves its downloads
+#[cfg(not(target_os = "macos"))]
+fn move_download(
+	from: &Path, to: PathBuf, policy: ConflictPolicy, console: ConsolePrinter,
+) -> Result<PathBuf, String> {
+	if from == to {
+		return Ok(to);
+	}
+	if let Some(dir) = to.parent().filter(|dir| !dir.exists()) {
+		console.debug(&format!("Creating directory: {:?}", dir));
+		create_dir_all(dir)
+			.map_err(|error| format!("Error creating directory: {}", error))?;
+	}
+	let to = resolve_conflict(to, policy)?;
+	copy(from, &to).map_err(|error| format!("Error copying file: {}", error))?;
+	if let Err(error) = remove_file(from) {
+		console.error(&format!("Error deleting file: {}", error));
+	}
+	Ok(to)
+}
+
+/// Saves a reassembled blob to where the window saves its downloads
+fn save_blob(blob: &BlobDownload) -> Result<PathBuf, String> {
+	if let Some(dir) = blob.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+		create_dir_all(dir)
+			.map_err(|error| format!("Error creating directory: {}", error))?;
+	}
+	let path = resolve_conflict(blob.path.clone(), blob.policy)?;
+	write(&path, &blob.data).map_err(|error| format!("Error saving blob: {}", error))?;
+	Ok(path)
+}
+
+/// Cancels a download of a window. Blobs stop being sent right away, while files
+/// saved by the webview are deleted once it is done with them
+fn cancel_download(
+	state: &mut WindowState, download: u64, console: ConsolePrinter,
+	request_id: Option<&Value>,
+) {
+	let index = match state
+		.downloads
+		.iter()
+		.position(|pending| pending.id == download && !pending.cancelled)
+	{
+		Some(index) => index,
+		None => {
+			let message = format!("No download {} in progress", download);
+			return console.send_error("download_not_found", &message, request_id);
+		}
+	};
+	match state.downloads[index].blob.is_some() {
+		true => {
+			state.downloads.remove(index);
+			let script = CANCEL_BLOB_JS.replace("{download}", &download.to_string());
+			state.webview.evaluate_script(&script).unwrap_or_default();
+		}
+		false => state.downloads[index].cancelled = true,
+	}
+	console.send(AckReply { ack: "cancel_download".to_string() }, request_id);
+	console.send(ClientEvent::DownloadCancelled { download }, state.request_id.as_ref());
+}
+
+/// Opens a link of a window in a new window, which follows the navigation policy of its opener
+/// # Arguments
+/// * `uri` - The url of the link
+/// * `window_icon` - The path of the icon of the new window
+/// * `opener` - The window the link was opened from
+/// # Returns
+/// * `Result<(), String>` - Nothing, or why the window can't be created
+fn open_popup(
+	uri: &str, window_icon: &str, opener: WindowId,
+	webviews: &mut HashMap<WindowId, WindowState>, proxy: &EventLoopProxy<UserEvent>,
+	console: ConsolePrinter, event_loop: &EventLoopWindowTarget<UserEvent>,
+) -> Result<(), String> {
+	let navigation = webviews
+		.get(&opener)
+		.and_then(|state| state.navigation.clone())
+		.unwrap_or_default();
+	let window = WindowBuilder::new()
+		.with_title(uri)
+		.with_window_icon(get_icon(window_icon))
+		.with_inner_size(LogicalSize::new(1300, 900))
+		.with_resizable(true)
+		.with_theme(Some(Theme::Dark))
+		.build(event_loop)
+		.map_err(|error| error.to_string())?;
+	let window_id = window.id();
+
+	let webview = WebViewBuilder::new(window).map_err(|error| error.to_string())?;
+	#[cfg(target_os = "windows")]
+	let mut cache_directory = web_context();
+	#[cfg(target_os = "windows")]
+	let webview = webview.with_web_context(&mut cache_directory);
+	let webview = add_handlers(
+		webview,
+		proxy,
+		window_id,
+		"".to_string(),
+		"".to_string(),
+		ConflictPolicy::default(),
+		ExportTarget::default(),
+		navigation.clone(),
+		window_icon,
+		Some(false),
+		console,
+	);
+	navigation.approve(uri);
+	let webview = webview
+		.with_url(uri)
+		.and_then(|webview| webview.build())
+		.map_err(|error| error.to_string())?;
+
+	let handle = WindowHandle::next();
+	console.send(ClientEvent::WindowCreated { window: handle.clone() }, None);
+	webviews.insert(
+		window_id,
+		WindowState {
+			navigation: Some(navigation),
+			..WindowState::new(webview, None, handle)
+		},
+	);
+	Ok(())
+}
+
+/// Carries out a command on the window its handle refers to
+fn handle_window_command(
+	command: WindowCommand, webviews: &mut HashMap<WindowId, WindowState>,
+	console: ConsolePrinter, request_id: Option<&Value>,
+) {
+	let target = command.window();
+	let window_id = match webviews.iter().find(|(_, state)| state.handle == *target) {
+		Some((window_id, _)) => *window_id,
+		None => {
+			let message = format!("No window with handle {}", target);
+			return console.send_error("window_not_found", &message, request_id);
+		}
+	};
+	let ack = AckReply { ack: command.name().to_string() };
+
+	// Streamed points are buffered by the listener, only errors are replied to
+	if matches!(command, WindowCommand::Stream { .. }) {
+		if let Some(frame) = StreamFrame::new(command) {
+			stream_to_window(frame, webviews, console);
+		}
+		return;
+	}
+	if let WindowCommand::CancelDownload { download, .. } = command {
+		let state = webviews.get_mut(&window_id).unwrap();
+		return cancel_download(state, download, console, request_id);
+	}
+	if let WindowCommand::Eval { id, script, timeout, .. } = command {
+		let state = webviews.get_mut(&window_id).unwrap();
+		return run_eval(state, &script, timeout, id, console);
+	}
+	if let WindowCommand::Close { .. } = command {
+		console.send(ack, request_id);
+		return close_window(webviews, window_id, console);
+	}
+
+	let webview = &webviews[&window_id].webview;
+	let window = webview.window();
+	match command {
+		WindowCommand::Focus { .. } => window.set_focus(),
+		WindowCommand::Minimize { .. } => window.set_minimized(true),
+		WindowCommand::Maximize { .. } => window.set_maximized(true),
+		WindowCommand::Restore { .. } => {
+			window.set_minimized(false);
+			window.set_maximized(false);
+		}
+		WindowCommand::Move { x, y, .. } => {
+			window.set_outer_position(LogicalPosition::new(x, y));
+		}
+		WindowCommand::Resize { width, height, .. } => {
+			window.set_inner_size(LogicalSize::new(width, height));
+		}
+		WindowCommand::SetTitle { title, .. } => window.set_title(&title),
+		WindowCommand::SetAlwaysOnTop { enabled, .. } => window.set_always_on_top(enabled),
+		WindowCommand::SetFullscreen { enabled, .. } => {
+			window.set_fullscreen(enabled.then_some(Fullscreen::Borderless(None)));
+		}
+		WindowCommand::OpenDevtools { .. } => webview.open_devtools(),
+		WindowCommand::Navigate { url, .. } => {
+			if let Some(navigation) = &webviews[&window_id].navigation {
+				navigation.approve(&url);
+			}
+			webview.load_url(&url);
+		}
+		WindowCommand::Update { html, json_data, figure, .. } => {
+			let html = match html.map(read_html).transpose() {
+				Err(error) => return console.send_error("invalid_request", &error, request_id),
+				Ok(html) => html.map(|html| match console.active {
+					true => format!("{}{}", DEV_TOOLS_HTML, html),
+					false => html,
+				}),
+			};
+			let update = json!({ "html": html, "json_data": json_data, "figure": figure });
+			if let Err(error) = webview.evaluate_script(&format!("pywry_update({});", update))
+			{
+				return console.send_error("window_error", &error.to_string(), request_id);
+			}
+		}
+		WindowCommand::Reply { call, result, error, .. } => {
+			let script =
+				format!("window.pywry._settle({}, {}, {});", call, result, json!(error));
+			if let Err(error) = webview.evaluate_script(&script) {
+				return console.send_error("window_error", &error.to_string(), request_id);
+			}
+		}
+		WindowCommand::Close { .. }
+		| WindowCommand::Stream { .. }
+		| WindowCommand::Eval { .. }
+		| WindowCommand::CancelDownload { .. } => {}
+	}
+	console.send(ack, request_id);
+}
+
+/// Fails every eval past its deadline
+fn expire_evals(
+	webviews: &mut HashMap<WindowId, WindowState>, console: ConsolePrinter,
+) {
+	let now = Instant::now();
+	for state in webviews.values_mut() {
+		state.evals.retain(|_, eval| {
+			if eval.deadline > now {
+				return true;
+			}
+			console.send_error(
+				"eval_timeout",
+				"Script did not finish before the timeout",
+				eval.request_id.as_ref(),
+			);
+			false
+		});
+	}
+}
+
+/// Returns the earliest deadline of the render jobs and evals running in any window,
+/// and of the queued render jobs
+pub fn next_deadline(
+	webviews: &HashMap<WindowId, WindowState>, jobs: &RenderQueue,
+) -> Option<Instant> {
+	webviews.values().filter_map(WindowState::deadline).chain(jobs.deadline()).min()
+}
+
+/// Appends the points of a frame to the Plotly figure of its window
+fn stream_to_window(
+	frame: StreamFrame, webviews: &HashMap<WindowId, WindowState>,
+	console: ConsolePrinter,
+) {
+	let request_id = frame.request_id.as_ref();
+	match webviews.values().find(|state| state.handle == frame.window) {
+		Some(state) => {
+			if let Err(error) = state.webview.evaluate_script(&frame.to_script()) {
+				console.send_error("window_error", &error.to_string(), request_id);
+			}
+		}
+		None => {
+			let message = format!("No window with handle {}", frame.window);
+			console.send_error("window_not_found", &message, request_id);
+		}
+	}
+}
+
+/// Hands queued render jobs to idle headless renderers, one job per renderer
+fn dispatch_render_jobs(
+	webviews: &mut HashMap<WindowId, WindowState>, jobs: &mut RenderQueue,
+	proxy: &EventLoopProxy<UserEvent>,
+) {
+	for (window_id, state) in webviews
+		.iter_mut()
+		.filter(|(_, state)| matches!(state.render_status, RenderStatus::Idle))
+	{
+		match jobs.jobs.pop_front() {
+			Some(job) => {
+				let deadline = Instant::now() + job.showable.timeout.unwrap_or(jobs.timeout);
+				state.render_status = RenderStatus::Busy(deadline);
+				proxy.send_event(UserEvent::NewPlot(job, *window_id)).unwrap_or_default();
+			}
+			None => break,
+		}
+	}
+}
+
+/// Marks a renderer idle once its job is done, returning the job's request id.
+/// Returns None when the renderer had no job, e.g. a result arriving after a timeout
+fn finish_render_job(
+	webviews: &mut HashMap<WindowId, WindowState>, window_id: WindowId,
+) -> Option<Option<Value>> {
+	let state = webviews.get_mut(&window_id)?;
+	match state.render_status {
+		RenderStatus::Busy(_) => {
+			state.render_status = RenderStatus::Idle;
+			Some(state.request_id.take())
+		}
+		_ => None,
+	}
+}
+
+/// Fails a render job and reloads its renderer, so a broken page can't poison the next job
+fn reset_renderer(
+	state: &mut WindowState, code: &str, message: &str, console: ConsolePrinter,
+) {
+	console.send_error(code, message, state.request_id.take().as_ref());
+	state.render_status = RenderStatus::Loading;
+	state.webview.load_url(HEADLESS_URL);
+}
+
+/// Fails every render job past its deadline, reloading the renderers running them so a
+/// hung `Plotly.toImage` can't poison the next job, and the queued jobs no renderer took
+fn expire_render_jobs(
+	webviews: &mut HashMap<WindowId, WindowState>, jobs: &mut RenderQueue,
+	console: ConsolePrinter,
+) {
+	let now = Instant::now();
+	jobs.jobs.retain(|job| {
+		if job.deadline > now {
+			return true;
+		}
+		console.send_error(
+			"render_timeout",
+			"No renderer was available before the timeout",
+			job.request_id.as_ref(),
+		);
+		false
+	});
+	for state in webviews.values_mut() {
+		if let RenderStatus::Busy(deadline) = state.render_status {
+			if deadline <= now {
+				let message = "Render did not finish before the timeout";
+				reset_renderer(state, "render_timeout", message, console.clone());
+			}
+		}
+	}
+}
+
 pub fn handle_events(
-	event: Event<UserEvent>, webviews: &mut HashMap<WindowId, WebView>,
-	_proxy: &EventLoopProxy<UserEvent>, console: ConsolePrinter,
+	event: Event<UserEvent>, webviews: &mut HashMap<WindowId, WindowState>,
+	jobs: &mut RenderQueue, _proxy: &EventLoopProxy<UserEvent>, console: ConsolePrinter,
 	_event_loop: &EventLoopWindowTarget<UserEvent>, headless: bool,
 ) {
 	match event {
 		// UserEvent::NewMessageReceived
-		Event::UserEvent(UserEvent::NewMessageReceived(message)) => {
+		Event::UserEvent(UserEvent::NewMessageReceived(message, request_id)) => {
 			console.debug("Received message from Python");
 			match headless {
-				true => {
-					let window_id = webviews.iter_mut().next().unwrap().0;
-					_proxy
-						.send_event(UserEvent::NewPlot(message, *window_id))
-						.unwrap_or_default();
-				}
+				true => match ShowableHeadless::new(&message) {
+					Err(error) => {
+						console.send_error("invalid_request", &error, request_id.as_ref())
+					}
+					// Every renderer failed to start, the job would wait for nothing
+					Ok(_) if webviews.is_empty() => console.send_error(
+						"render_failed",
+						"No headless renderer is running",
+						request_id.as_ref(),
+					),
+					Ok(showable) => {
+						jobs.push(showable, request_id);
+						dispatch_render_jobs(webviews, jobs, _proxy);
+					}
+				},
 				false => {
-					let chart = Showable::new(&message).unwrap_or_default();
+					let chart = match Showable::new(&message) {
+						Err(error) => {
+							console.send_error("invalid_request", &error, request_id.as_ref());
+							return;
+						}
+						Ok(chart) => chart,
+					};
+					let handle = match chart.label.clone() {
+						Some(label) => WindowHandle::Label(label),
+						None => WindowHandle::next(),
+					};
+					if webviews.values().any(|state| state.handle == handle) {
+						let message = format!("A window with handle {} is already open", handle);
+						console.send_error("label_in_use", &message, request_id.as_ref());
+						return;
+					}
+					let export_to = chart.export_to;
+					let navigation = chart.navigation.clone();
 					match create_new_window(chart, &_event_loop, &_proxy, console) {
-						Err(error) => console.error(&format!("Error creating window: {}", error)),
+						Err(error) => console.send_error(
+							"window_error",
+							&format!("Error creating window: {}", error),
+							request_id.as_ref(),
+						),
 						Ok(new_window) => {
-							webviews.insert(new_window.0, new_window.1);
+							console.send(
+								ClientEvent::WindowCreated { window: handle.clone() },
+								request_id.as_ref(),
+							);
+							webviews.insert(
+								new_window.0,
+								WindowState {
+									export_to,
+									navigation: Some(navigation),
+									relay: new_window.2,
+									..WindowState::new(new_window.1, request_id, handle)
+								},
+							);
 						}
 					};
 				}
 			}
 		}
+		// UserEvent::CommandReceived
+		Event::UserEvent(UserEvent::CommandReceived(message, request_id)) => {
+			console.debug("Received command from Python");
+			match (headless, WindowCommand::from_json(&message)) {
+				(true, _) => console.send_error(
+					"unsupported",
+					"Window commands are not available in headless mode",
+					request_id.as_ref(),
+				),
+				(false, Err(error)) => {
+					console.send_error("invalid_request", &error, request_id.as_ref())
+				}
+				(false, Ok(command)) => {
+					handle_window_command(command, webviews, console, request_id.as_ref())
+				}
+			}
+		}
+		// UserEvent::StreamFrames
+		Event::UserEvent(UserEvent::StreamFrames(frames)) => {
+			for frame in frames {
+				match headless {
+					true => console.send_error(
+						"unsupported",
+						"Window commands are not available in headless mode",
+						frame.request_id.as_ref(),
+					),
+					false => stream_to_window(frame, webviews, console.clone()),
+				}
+			}
+		}
 		// UserEvent::STDout
-		Event::UserEvent(UserEvent::STDout(result)) => {
-			std::thread::spawn(move || {
-				let stdout: io::Stdout = io::stdout();
-				let mut handler = stdout.lock();
-				let decoded = urldecode(&result).unwrap_or_default();
-				handler
-					.write_all(
-						format!("{}\n", serde_json::json!({ "result": decoded })).as_bytes(),
-					)
-					.unwrap();
-				handler.flush().unwrap();
-			});
+		Event::UserEvent(UserEvent::STDout(result, window_id)) => {
+			let decoded = urldecode(&result).unwrap_or_default();
+			match headless {
+				true => match finish_render_job(webviews, window_id) {
+					Some(request_id) => {
+						console.send(
+							ResultReply { result: decoded.to_string().into() },
+							request_id.as_ref(),
+						);
+						dispatch_render_jobs(webviews, jobs, _proxy);
+					}
+					None => console.debug("Discarding result from a timed out render"),
+				},
+				false => {
+					let request_id =
+						webviews.get(&window_id).and_then(|state| state.request_id.as_ref());
+					console.send(ResultReply { result: decoded.to_string().into() }, request_id);
+				}
+			}
+		}
+		// UserEvent::RenderFailed
+		Event::UserEvent(UserEvent::RenderFailed(error, window_id)) => {
+			if let Some(request_id) = finish_render_job(webviews, window_id) {
+				match serde_json::from_str::<ErrorInfo>(&error) {
+					Ok(error) => console.send(ErrorReply { error }, request_id.as_ref()),
+					Err(_) => console.send_error("render_failed", &error, request_id.as_ref()),
+				}
+				dispatch_render_jobs(webviews, jobs, _proxy);
+			}
+		}
+		// UserEvent::RendererReady
+		Event::UserEvent(UserEvent::RendererReady(window_id)) => {
+			if let Some(state) = webviews.get_mut(&window_id) {
+				console.debug("Renderer Ready");
+				state.render_status = RenderStatus::Idle;
+			}
+			dispatch_render_jobs(webviews, jobs, _proxy);
+		}
+		// StartCause::ResumeTimeReached
+		Event::NewEvents(StartCause::ResumeTimeReached { .. }) => {
+			expire_evals(webviews, console.clone());
+			flush_due_geometry_events(webviews, console.clone());
+			if headless {
+				expire_render_jobs(webviews, jobs, console);
+			}
+		}
+		// UserEvent::EvalResult
+		Event::UserEvent(UserEvent::EvalResult(message, window_id)) => {
+			let outcome = match serde_json::from_str::<EvalOutcome>(&message) {
+				Ok(outcome) => outcome,
+				Err(error) => return console.error(&format!("Invalid eval result: {}", error)),
+			};
+			// Evals that timed out were already replied to
+			let eval = match webviews
+				.get_mut(&window_id)
+				.and_then(|state| state.evals.remove(&outcome.eval))
+			{
+				Some(eval) => eval,
+				None => return console.debug("Discarding the result of an expired eval"),
+			};
+			match outcome.error {
+				Some(error) => console.send(ErrorReply { error }, eval.request_id.as_ref()),
+				None => {
+					console.send(ResultReply { result: outcome.result }, eval.request_id.as_ref())
+				}
+			}
+		}
+		// UserEvent::CallReceived
+		Event::UserEvent(UserEvent::CallReceived(message, window_id)) => {
+			let state = match webviews.get(&window_id) {
+				Some(state) => state,
+				None => return console.debug("Webview not found"),
+			};
+			match serde_json::from_str::<PageCall>(&message) {
+				Err(error) => console.error(&format!("Invalid window.pywry.call: {}", error)),
+				Ok(PageCall { call, name, args }) => console.send(
+					ClientEvent::Call { window: state.handle.clone(), call, name, args },
+					None,
+				),
+			}
+		}
+		// UserEvent::ConsoleMessage
+		Event::UserEvent(UserEvent::ConsoleMessage(message, window_id)) => {
+			let state = match webviews.get(&window_id) {
+				Some(state) => state,
+				None => return console.debug("Webview not found"),
+			};
+			match serde_json::from_str::<ConsoleRecord>(&message) {
+				Err(error) => console.error(&format!("Invalid console message: {}", error)),
+				Ok(ConsoleRecord { level, message, source, line, column }) => console.send(
+					ClientEvent::Console {
+						window: state.handle.clone(),
+						level,
+						message,
+						source,
+						line,
+						column,
+					},
+					None,
+				),
+			}
+		}
+		// UserEvent::PlotlyEvent
+		Event::UserEvent(UserEvent::PlotlyEvent(message, window_id)) => {
+			let state = match webviews.get(&window_id) {
+				Some(state) => state,
+				None => return console.debug("Webview not found"),
+			};
+			match serde_json::from_str::<PlotlyEventMessage>(&message) {
+				Err(error) => console.error(&format!("Invalid Plotly event: {}", error)),
+				Ok(PlotlyEventMessage { name, data }) => console
+					.send(ClientEvent::Plotly { window: state.handle.clone(), name, data }, None),
+			}
+		}
+		// UserEvent::AssetDenied
+		Event::UserEvent(UserEvent::AssetDenied(path, window_id)) => {
+			if let Some(state) = webviews.get(&window_id) {
+				console
+					.send(ClientEvent::AssetDenied { window: state.handle.clone(), path }, None);
+			}
+		}
+		// UserEvent::Notify
+		Event::UserEvent(UserEvent::Notify(message, window_id)) => {
+			let request_id =
+				webviews.get(&window_id).and_then(|state| state.request_id.as_ref());
+			console.send(message, request_id);
 		}
 		// UserEvent::NewPlot
-		Event::UserEvent(UserEvent::NewPlot(data, _windowid)) => {
-			let plot_data = PlotData::to_json(&data).to_string();
+		Event::UserEvent(UserEvent::NewPlot(job, window_id)) => {
+			let plot_data = PlotData::new(&job.showable).to_json().to_string();
 
-			webviews
-				.iter_mut()
-				.next()
-				.unwrap()
-				.1
-				.evaluate_script(&format!("plotly_render({});", plot_data))
-				.unwrap();
+			match webviews.get_mut(&window_id) {
+				Some(state) => {
+					state.request_id = job.request_id;
+					let script = format!("plotly_render({});", plot_data);
+					if let Err(error) = state.webview.evaluate_script(&script) {
+						let message = format!("Error starting the render: {}", error);
+						reset_renderer(state, "render_failed", &message, console);
+					}
+				}
+				None => console.send_error(
+					"renderer_not_found",
+					"Headless renderer not found",
+					job.request_id.as_ref(),
+				),
+			}
 		}
 		// UserEvent::NewWindowCreated
 		Event::UserEvent(UserEvent::NewWindowCreated(window_id)) => {
 			console.debug("New Window Created");
 			match webviews.get_mut(&window_id) {
-				Some(webview) => {
-					webview.window().set_always_on_top(false);
+				Some(state) => {
+					state.webview.window().set_always_on_top(false);
 				}
 				None => {}
 			}
 		}
 		// UserEvent::DownloadStarted
-		#[cfg(not(target_os = "macos"))]
-		Event::UserEvent(UserEvent::DownloadStarted(uri, path)) => {
+		Event::UserEvent(UserEvent::DownloadStarted(download, uri, path, window_id)) => {
 			if uri.len() < 200 {
 				console.debug(&format!("Download Started: {}", uri));
 			}
-			console.debug(&format!("Path: {}", path));
+			if let Some(state) = webviews.get_mut(&window_id) {
+				state.downloads.push(Download {
+					id: download,
+					uri,
+					cancelled: false,
+					blob: None,
+				});
+				console.send(
+					ClientEvent::DownloadStarted { download, path },
+					state.request_id.as_ref(),
+				);
+			}
 		}
 		// UserEvent::DownloadComplete
 		#[cfg(not(target_os = "macos"))]
 		Event::UserEvent(UserEvent::DownloadComplete(
+			uri,
 			filepath,
 			success,
 			download_path,
 			export_image,
+			policy,
 			window_id,
 		)) => {
 			let is_export = !export_image.is_empty();
 			console.debug(&format!("Download Complete: {}", success));
 
-			let decoded = decode_path(&filepath.unwrap().to_str().unwrap());
+			let state = match webviews.get_mut(&window_id) {
+				Some(state) => state,
+				None => return console.debug("Webview not found"),
+			};
+			let download =
+				take_download(state, |download| download.blob.is_none() && download.uri == uri);
+			let request_id = state.request_id.as_ref();
+			let decoded = decode_path(&filepath.unwrap_or_default().to_string_lossy());
+
+			match &download {
+				Some(Download { cancelled: true, .. }) => {
+					if let Err(error) = remove_file(&decoded) {
+						console.error(&format!("Error deleting file: {}", error));
+					}
+					return;
+				}
+				_ if !success => {
+					return console.send_error(
+						"download_failed",
+						"The download failed",
+						request_id,
+					);
+				}
+				_ => {}
+			}
+
+			if state.export_to == ExportTarget::Memory {
+				let download = download.map_or(0, |download| download.id);
+				send_export_file(download, &decoded, console, request_id);
+				_proxy.send_event(UserEvent::CloseWindow(window_id)).unwrap_or_default();
+				return;
+			}
 
 			let new_path = match !download_path.is_empty() {
 				true => match !export_image.is_empty() {
@@ -136,66 +906,244 @@ pub fn handle_events(
 			console.debug(&format!("Original Path: {:?}", decoded));
 			console.debug(&format!("New Path: {:?}", new_path));
 
-			let dir = new_path.parent().unwrap();
-			match !dir.exists() {
-				true => {
-					console.debug(&format!("Creating directory: {:?}", dir));
-					if let Err(error) = create_dir_all(dir) {
-						console.error(&format!("Error creating directory: {}", error));
+			let download = download.map_or(0, |download| download.id);
+			let save = move || move_download(&decoded, new_path, policy, console);
+			finish_download(download, save, is_export, window_id, _proxy);
+		}
+		// UserEvent::DownloadSaved
+		#[cfg(target_os = "macos")]
+		Event::UserEvent(UserEvent::DownloadSaved(uri, filepath, success, window_id)) => {
+			let state = match webviews.get_mut(&window_id) {
+				Some(state) => state,
+				None => return console.debug("Webview not found"),
+			};
+			let download = take_download(state, |download| download.uri == uri);
+			let request_id = state.request_id.as_ref();
+			let path = filepath.unwrap_or_default();
+
+			match &download {
+				Some(Download { cancelled: true, .. }) => {
+					if let Err(error) = remove_file(&path) {
+						console.error(&format!("Error deleting file: {}", error));
+					}
+				}
+				_ if !success => {
+					console.send_error("download_failed", "The download failed", request_id)
+				}
+				_ => {
+					let download = download.map_or(0, |download| download.id);
+					match state.export_to {
+						ExportTarget::Memory => {
+							send_export_file(download, &path, console, request_id)
+						}
+						ExportTarget::File => {
+							finish_download(download, move || Ok(path), false, window_id, _proxy)
+						}
 					}
 				}
-				false => {}
 			}
-
-			match copy(&decoded, &new_path) {
-				Err(error) => console.error(&format!("Error copying file: {}", error)),
-				Ok(_) => {
-					if is_export {
+		}
+		// UserEvent::DownloadFailed
+		Event::UserEvent(UserEvent::DownloadFailed(download, error, window_id)) => {
+			if let Some(state) = webviews.get_mut(&window_id) {
+				take_download(state, |pending| pending.id == download);
+				let message = format!("Download {} failed: {}", download, error);
+				console.send_error("download_failed", &message, state.request_id.as_ref());
+			}
+		}
+		// UserEvent::DownloadFinished
+		Event::UserEvent(UserEvent::DownloadFinished(
+			download,
+			outcome,
+			close,
+			window_id,
+		)) => {
+			let request_id =
+				webviews.get(&window_id).and_then(|state| state.request_id.as_ref());
+			match outcome {
+				Err(error) => console.send_error("download_failed", &error, request_id),
+				Ok((path, size, sha256)) => {
+					let complete = ClientEvent::DownloadComplete { download, path, size, sha256 };
+					console.send(complete, request_id);
+					if close {
 						_proxy.send_event(UserEvent::CloseWindow(window_id)).unwrap_or_default();
 					}
-					if let Err(error) = remove_file(&decoded) {
-						console.error(&format!("Error deleting file: {}", error));
-					}
 				}
 			}
 		}
 		// UserEvent::CloseWindow
 		Event::UserEvent(UserEvent::CloseWindow(window_id)) => {
 			console.debug("Closing Window");
-			match webviews.get(&window_id) {
-				Some(_) => {
-					console.debug("Closing Webview");
-					webviews.remove(&window_id);
-				}
-				None => console.debug("Webview not found"),
+			close_window(webviews, window_id, console);
+		}
+		// UserEvent::BlobReceived
+		#[cfg(not(target_os = "macos"))]
+		Event::UserEvent(UserEvent::BlobReceived(
+			download,
+			uri,
+			default_path,
+			download_path,
+			export_image,
+			policy,
+			window_id,
+		)) => {
+			console.debug("Blob Download Started");
+			let state = match webviews.get_mut(&window_id) {
+				Some(state) => state,
+				None => return console.debug("Webview not found"),
+			};
+			let script = BLOB_DOWNLOAD_JS
+				.replace("{uri}", &json!(uri).to_string())
+				.replace("{download}", &download.to_string());
+			if let Err(error) = state.webview.evaluate_script(&script) {
+				let message = format!("Error reading blob: {}", error);
+				return console.send_error(
+					"download_failed",
+					&message,
+					state.request_id.as_ref(),
+				);
+			}
+			let path = download_target(&default_path, &download_path, &export_image);
+			console.send(
+				ClientEvent::DownloadStarted { download, path: path.clone() },
+				state.request_id.as_ref(),
+			);
+			state.downloads.push(Download {
+				id: download,
+				uri,
+				cancelled: false,
+				blob: Some(BlobDownload {
+					path,
+					data: Vec::new(),
+					total: None,
+					is_export: !export_image.is_empty(),
+					policy,
+				}),
+			});
+		}
+		// UserEvent::BlobStarted
+		Event::UserEvent(UserEvent::BlobStarted(download, total, window_id)) => {
+			if let Some(blob) = webviews
+				.get_mut(&window_id)
+				.and_then(|state| {
+					state.downloads.iter_mut().find(|pending| pending.id == download)
+				})
+				.and_then(|pending| pending.blob.as_mut())
+			{
+				blob.total = total;
 			}
 		}
 		// UserEvent::BlobChunk
-		Event::UserEvent(UserEvent::BlobChunk(_)) => {
-			console.debug("Blob Chunk");
+		Event::UserEvent(UserEvent::BlobChunk(download, Some(chunk), window_id)) => {
+			let state = match webviews.get_mut(&window_id) {
+				Some(state) => state,
+				None => return console.debug("Webview not found"),
+			};
+			let blob = match state
+				.downloads
+				.iter_mut()
+				.find(|pending| pending.id == download)
+				.and_then(|pending| pending.blob.as_mut())
+			{
+				Some(blob) => blob,
+				None => return console.debug("Discarding a chunk of a cancelled download"),
+			};
+			match chunk.split_once(";base64,").and_then(|(_, data)| BASE64.decode(data).ok())
+			{
+				Some(bytes) => {
+					blob.data.extend(bytes);
+					let progress = ClientEvent::DownloadProgress {
+						download,
+						received: blob.data.len() as u64,
+						total: blob.total,
+					};
+					console.send(progress, state.request_id.as_ref());
+				}
+				None => {
+					take_download(state, |pending| pending.id == download);
+					console.send_error(
+						"download_failed",
+						"Invalid blob chunk, expected a base64 data URL",
+						state.request_id.as_ref(),
+					);
+				}
+			}
+		}
+		// UserEvent::BlobChunk, once every chunk was sent
+		Event::UserEvent(UserEvent::BlobChunk(download, None, window_id)) => {
+			let state = match webviews.get_mut(&window_id) {
+				Some(state) => state,
+				None => return console.debug("Webview not found"),
+			};
+			let blob = match take_download(state, |pending| pending.id == download) {
+				Some(Download { blob: Some(blob), .. }) => blob,
+				_ => return console.debug("Discarding a cancelled download"),
+			};
+			match state.export_to {
+				ExportTarget::Memory => {
+					let request_id = state.request_id.as_ref();
+					send_export(download, &blob.path, &blob.data, console, request_id);
+					if blob.is_export {
+						_proxy.send_event(UserEvent::CloseWindow(window_id)).unwrap_or_default();
+					}
+				}
+				ExportTarget::File => {
+					console.debug(&format!("Saving blob to {:?}", blob.path));
+					let is_export = blob.is_export;
+					finish_download(
+						download,
+						move || save_blob(&blob),
+						is_export,
+						window_id,
+						_proxy,
+					);
+				}
+			}
 		}
 		// WindowEvent::CloseRequested
 		Event::WindowEvent { event: WindowEvent::CloseRequested, window_id, .. } => {
 			console.debug("Close Requested");
-			match webviews.get(&window_id) {
-				Some(_) => {
-					console.debug("Closing Webview");
-					webviews.remove(&window_id);
-				}
-				None => console.debug("Webview not found"),
+			close_window(webviews, window_id, console);
+		}
+		// WindowEvent::Resized, Moved and Focused
+		Event::WindowEvent {
+			event:
+				event
+				@ (WindowEvent::Resized(_) | WindowEvent::Moved(_) | WindowEvent::Focused(_)),
+			window_id,
+			..
+		} if !headless => {
+			if let Some(state) = webviews.get_mut(&window_id) {
+				send_window_event(&event, state, console);
 			}
 		}
 		// UserEvent::DevTools
 		Event::UserEvent(UserEvent::DevTools(window_id)) => {
 			console.debug("DevTools");
 			match webviews.get(&window_id) {
-				Some(webview) => {
+				Some(state) => {
 					console.debug("Opening DevTools");
-					webview.open_devtools();
+					state.webview.open_devtools();
 				}
 				None => console.debug("Webview not found"),
 			}
 		}
+		// UserEvent::Navigation
+		Event::UserEvent(UserEvent::Navigation(url, new_window, action, window_id)) => {
+			if let Some(state) = webviews.get(&window_id) {
+				let window = state.handle.clone();
+				console.send(
+					ClientEvent::Navigation { window, url, new_window, action },
+					state.request_id.as_ref(),
+				);
+			}
+		}
+		// UserEvent::OpenBrowser
+		Event::UserEvent(UserEvent::OpenBrowser(url)) => {
+			if let Err(error) = open::that(&url) {
+				console.error(&format!("Error opening {} in the browser: {}", url, error));
+			}
+		}
 		// UserEvent::OpenFile
 		Event::UserEvent(UserEvent::OpenFile(filepath)) => {
 			if filepath.is_some() {
@@ -208,44 +1156,23 @@ pub fn handle_events(
 			}
 		}
 		// WindowEvent::NewWindow
-		#[cfg(not(target_os = "windows"))]
-		Event::UserEvent(UserEvent::NewWindow(uri, window_icon)) => {
+		Event::UserEvent(UserEvent::NewWindow(uri, window_icon, opener)) => {
 			console.debug(&format!("New Window Requested: {}", uri));
-			match (uri.starts_with("http://") || uri.starts_with("https://"))
-				&& !uri.starts_with("https://ogs.google.com")
-			{
-				true => {
-					let pre_window = WindowBuilder::new()
-						.with_title(uri.to_string())
-						.with_window_icon(window_icon)
-						.with_inner_size(LogicalSize::new(1300, 900))
-						.with_resizable(true)
-						.with_theme(Some(Theme::Dark));
-
-					let window = match pre_window.build(_event_loop) {
-						Err(error) => {
-							console.error(&format!("Window Creation Error: {}", error));
-							return;
-						}
-						Ok(item) => item,
-					};
-
-					let window_id = window.id();
-
-					let webview = WebViewBuilder::new(window)
-						.unwrap()
-						.with_url(&uri)
-						.unwrap()
-						.build()
-						.unwrap();
-
-					webviews.insert(window_id, webview);
-
-					console.debug("New Window Created");
-				}
-				false => {
-					console.debug(&format!("Invalid URI tried to open in new window: {}", uri));
-				}
+			if !uri.starts_with("http://") && !uri.starts_with("https://") {
+				return console
+					.debug(&format!("Invalid URI tried to open in new window: {}", uri));
+			}
+			match open_popup(
+				&uri,
+				&window_icon,
+				opener,
+				webviews,
+				_proxy,
+				console,
+				_event_loop,
+			) {
+				Ok(()) => console.debug("New Window Created"),
+				Err(error) => console.error(&format!("Window Creation Error: {}", error)),
 			}
 		}
 		_ => {}
@@ -1,21 +1,70 @@
 use crate::{
 	constants,
-	structs::{ConsolePrinter, UserEvent},
+	navigation::NavigationPolicy,
+	protocol::{ConflictPolicy, ExportTarget, NavigationAction},
+	structs::{ConsolePrinter, Download, UserEvent},
+	utils::{download_target, export_temp_path},
 };
-use std::path::PathBuf;
 
-#[cfg(not(target_os = "windows"))]
-use crate::utils::get_icon;
+#[cfg(target_os = "macos")]
+use crate::utils::resolve_conflict;
+use std::{path::PathBuf, rc::Rc};
 
 use wry::{
 	application::{event_loop::EventLoopProxy, window::WindowId},
 	webview::WebViewBuilder,
 };
 
+/// Parses the messages a page sends while transferring the blob of a download,
+/// e.g. `#BLOB_CHUNK:<download>:<data URL>`
+fn blob_event(message: &str, window_id: WindowId) -> Option<UserEvent> {
+	let (step, rest) = message.split_once(':')?;
+	let (download, payload) = rest.split_once(':')?;
+	let download = download.parse().ok()?;
+	match step {
+		"#BLOB_START" => {
+			Some(UserEvent::BlobStarted(download, payload.parse().ok(), window_id))
+		}
+		"#BLOB_CHUNK" => {
+			Some(UserEvent::BlobChunk(download, Some(payload.to_string()), window_id))
+		}
+		"#BLOB_END" => Some(UserEvent::BlobChunk(download, None, window_id)),
+		"#BLOB_ERROR" => {
+			Some(UserEvent::DownloadFailed(download, payload.to_string(), window_id))
+		}
+		_ => None,
+	}
+}
+
+/// Carries out what the navigation policy of a window decided for a url,
+/// and tells the client about it when the policy asks to
+fn dispatch_navigation(
+	proxy: &EventLoopProxy<UserEvent>, navigation: &NavigationPolicy, url: String,
+	new_window: bool, action: NavigationAction, window_id: WindowId, window_icon: &str,
+) {
+	let opened = match action {
+		NavigationAction::Window => {
+			Some(UserEvent::NewWindow(url.clone(), window_icon.to_string(), window_id))
+		}
+		NavigationAction::Browser => Some(UserEvent::OpenBrowser(url.clone())),
+		NavigationAction::Allow | NavigationAction::Block => None,
+	};
+	if let Some(event) = opened {
+		proxy.send_event(event).unwrap_or_default();
+	}
+	if navigation.notify {
+		proxy
+			.send_event(UserEvent::Navigation(url, new_window, action, window_id))
+			.unwrap_or_default();
+	}
+}
+
 pub fn add_handlers<'a>(
 	init_view: WebViewBuilder<'a>, proxy: &'a EventLoopProxy<UserEvent>,
-	window_id: WindowId, download_path: String, export_image: String, window_icon: &str,
-	is_headless: Option<bool>, console: ConsolePrinter,
+	window_id: WindowId, download_path: String, export_image: String,
+	download_conflict: ConflictPolicy, export_to: ExportTarget,
+	navigation: Rc<NavigationPolicy>, window_icon: &str, is_headless: Option<bool>,
+	console: ConsolePrinter,
 ) -> WebViewBuilder<'a> {
 	let _is_export = !export_image.is_empty();
 	let is_headless = is_headless.unwrap_or_default();
@@ -28,18 +77,45 @@ pub fn add_handlers<'a>(
 	return init_view
 		.with_download_started_handler({
 			let _proxy = proxy.clone();
+			let _download_path = download_path.clone();
+			let _export_image = export_image.clone();
 			move |_uri: String, default_path| {
+				let download = Download::next_id();
+				// Exports kept in memory are saved to a temporary file that is read back
+				let temp_path = match export_to {
+					ExportTarget::Memory => Some(export_temp_path(
+						download,
+						&download_target(default_path, "", &_export_image),
+					)),
+					ExportTarget::File => None,
+				};
+
 				#[cfg(not(target_os = "macos"))]
 				{
+					// The webview can't save blobs itself, the page sends them over IPC instead
 					if _uri.starts_with("blob:") {
-						let submitted =
-							_proxy.send_event(UserEvent::BlobReceived(_uri, window_id)).is_ok();
-						return submitted;
+						_proxy
+							.send_event(UserEvent::BlobReceived(
+								download,
+								_uri,
+								default_path.clone(),
+								_download_path.clone(),
+								_export_image.clone(),
+								download_conflict,
+								window_id,
+							))
+							.unwrap_or_default();
+						return false;
+					}
+					if let Some(temp_path) = temp_path {
+						*default_path = temp_path;
 					}
 					let submitted = _proxy
 						.send_event(UserEvent::DownloadStarted(
-							_uri.clone(),
-							default_path.display().to_string(),
+							download,
+							_uri,
+							default_path.clone(),
+							window_id,
 						))
 						.is_ok();
 
@@ -48,7 +124,9 @@ pub fn add_handlers<'a>(
 
 				#[cfg(target_os = "macos")]
 				{
-					if _is_export {
+					if let Some(temp_path) = temp_path {
+						*default_path = temp_path;
+					} else if _is_export {
 						let mut path = PathBuf::from(&export_image);
 						if path.is_dir() {
 							path.push(default_path.file_name().unwrap());
@@ -61,7 +139,21 @@ pub fn add_handlers<'a>(
 						}
 						*default_path = path.clone();
 					}
-					true
+					match resolve_conflict(default_path.clone(), download_conflict) {
+						Err(error) => {
+							_proxy
+								.send_event(UserEvent::DownloadFailed(download, error, window_id))
+								.unwrap_or_default();
+							false
+						}
+						Ok(path) => {
+							*default_path = path.clone();
+							_proxy
+								.send_event(UserEvent::DownloadStarted(download, _uri, path, window_id))
+								.unwrap_or_default();
+							true
+						}
+					}
 				}
 			}
 		})
@@ -70,24 +162,50 @@ pub fn add_handlers<'a>(
 			move |_, string| match string.as_str() {
 				_ if string.starts_with("#PYWRY_RESULT:") => {
 					proxy
-						.send_event(UserEvent::STDout(string[14..].to_string()))
+						.send_event(UserEvent::STDout(string[14..].to_string(), window_id))
 						.unwrap_or_default();
 
 					if !is_headless && !console.active {
 						proxy.send_event(UserEvent::CloseWindow(window_id)).unwrap_or_default();
 					}
 				}
-				_ if string.starts_with("data:") => {
-					proxy.send_event(UserEvent::BlobChunk(Some(string))).unwrap_or_default();
+				_ if string.starts_with("#PYWRY_CALL:") => {
+					proxy
+						.send_event(UserEvent::CallReceived(string[12..].to_string(), window_id))
+						.unwrap_or_default();
+				}
+				_ if string.starts_with("#EVAL_RESULT:") => {
+					proxy
+						.send_event(UserEvent::EvalResult(string[13..].to_string(), window_id))
+						.unwrap_or_default();
 				}
-				"#EOF" => {
-					proxy.send_event(UserEvent::BlobChunk(None)).unwrap_or_default();
+				_ if string.starts_with("#PLOTLY_EVENT:") => {
+					proxy
+						.send_event(UserEvent::PlotlyEvent(string[14..].to_string(), window_id))
+						.unwrap_or_default();
 				}
+				_ if string.starts_with("#CONSOLE:") => {
+					proxy
+						.send_event(UserEvent::ConsoleMessage(string[9..].to_string(), window_id))
+						.unwrap_or_default();
+				}
+				_ if string.starts_with("#BLOB_") => match blob_event(&string, window_id) {
+					Some(event) => proxy.send_event(event).unwrap_or_default(),
+					None => console.debug("Invalid blob message"),
+				},
 				_ if string.starts_with("#OPEN_FILE:") => {
 					proxy
 						.send_event(UserEvent::OpenFile(Some(PathBuf::from(&string[11..]))))
 						.unwrap_or_default();
 				}
+				_ if string.starts_with("#RENDER_ERROR:") => {
+					proxy
+						.send_event(UserEvent::RenderFailed(string[14..].to_string(), window_id))
+						.unwrap_or_default();
+				}
+				"#RENDERER_READY" => {
+					proxy.send_event(UserEvent::RendererReady(window_id)).unwrap_or_default();
+				}
 				"#DEVTOOLS" => {
 					proxy.send_event(UserEvent::DevTools(window_id)).unwrap_or_default();
 				}
@@ -102,41 +220,71 @@ pub fn add_handlers<'a>(
 				#[cfg(not(target_os = "macos"))]
 				proxy
 					.send_event(UserEvent::DownloadComplete(
+						_uri,
 						Some(_filepath),
 						success,
 						download_path.clone(),
 						export_image.clone(),
+						download_conflict,
 						window_id,
 					))
 					.unwrap_or_default();
 
 				#[cfg(target_os = "macos")]
 				{
+					proxy
+						.send_event(UserEvent::DownloadSaved(
+							_uri,
+							Some(_filepath),
+							success,
+							window_id,
+						))
+						.unwrap_or_default();
 					if success && _is_export {
 						proxy.send_event(UserEvent::CloseWindow(window_id)).unwrap_or_default();
 					}
 				}
 			}
 		})
-		.with_new_window_req_handler({
-			let _window_icon = window_icon.to_string();
-			#[cfg(not(target_os = "windows"))]
-			{
-				let proxy = proxy.clone();
-				move |uri: String| {
-					let submitted = proxy
-						.send_event(UserEvent::NewWindow(uri.clone(), get_icon(&_window_icon)))
-						.is_ok();
-					submitted
-				}
+		.with_navigation_handler({
+			let proxy = proxy.clone();
+			let navigation = navigation.clone();
+			let window_icon = window_icon.to_string();
+			move |uri: String| {
+				let action = navigation.navigate(&uri);
+				dispatch_navigation(
+					&proxy,
+					&navigation,
+					uri,
+					false,
+					action,
+					window_id,
+					&window_icon,
+				);
+				action == NavigationAction::Allow
 			}
-			#[cfg(target_os = "windows")]
-			{
-				move |_uri: String| true
+		})
+		.with_new_window_req_handler({
+			let proxy = proxy.clone();
+			let window_icon = window_icon.to_string();
+			// The webview never opens windows itself, pywry does when the policy allows it
+			move |uri: String| {
+				let action = navigation.new_window(&uri);
+				dispatch_navigation(
+					&proxy,
+					&navigation,
+					uri,
+					true,
+					action,
+					window_id,
+					&window_icon,
+				);
+				false
 			}
 		})
 		.with_initialization_script(constants::BLOBINIT_SCRIPT)
 		.with_initialization_script(constants::PYWRY_WINDOW_SCRIPT)
 		.with_initialization_script(constants::PLOTLY_RENDER_JS)
+		.with_initialization_script(constants::UPDATE_JS)
 		.with_initialization_script(maxos_script);
 }
@@ -1,15 +1,18 @@
 use crate::{
 	constants,
-	events::handle_events,
+	events::{handle_events, next_deadline},
 	handlers::add_handlers,
+	navigation::NavigationPolicy,
 	pipe::run_listener,
-	structs::{ConsolePrinter, ShowableHeadless, UserEvent},
-	utils::decode_path,
-};
-use std::{
-	collections::HashMap,
-	fs::{canonicalize, read},
+	protocol::{ClientEvent, ConflictPolicy, ExportTarget, WindowHandle},
+	response::MemoryAsset,
+	router::{Mount, MountSource, Router},
+	structs::{
+		ConsolePrinter, HeadlessOptions, RenderQueue, ShowableHeadless, UserEvent,
+		WindowState,
+	},
 };
+use std::{borrow::Cow, collections::HashMap, rc::Rc};
 
 use wry::{
 	application::{
@@ -17,7 +20,6 @@ use wry::{
 		event_loop::{ControlFlow, EventLoop, EventLoopProxy, EventLoopWindowTarget},
 		window::{WindowBuilder, WindowId},
 	},
-	http::{header::CONTENT_TYPE, Response},
 	webview::{WebView, WebViewBuilder},
 };
 
@@ -27,14 +29,16 @@ use wry::application::event_loop::EventLoopBuilder;
 /// Creates a new headless window and returns the window id and webview
 /// # Arguments
 /// * `to_show` - The Showable struct that contains the information to show
+/// * `plotly_js` - The Plotly.js bundle served to the webview
 /// * `event_loop` - The event loop to create the window on
 /// * `proxy` - The event loop proxy to send events to
 /// * `console` - The ConsolePrinter struct to print log messages to the console
 /// # Returns
 /// * `Result<(WindowId, WebView), String>` - The window id and webview or an error message
 fn create_new_window_headless(
-	to_show: ShowableHeadless, event_loop: &&EventLoopWindowTarget<UserEvent>,
-	proxy: &EventLoopProxy<UserEvent>, console: ConsolePrinter,
+	to_show: ShowableHeadless, plotly_js: Cow<'static, [u8]>,
+	event_loop: &&EventLoopWindowTarget<UserEvent>, proxy: &EventLoopProxy<UserEvent>,
+	console: ConsolePrinter,
 ) -> Result<(WindowId, WebView), String> {
 	let content = constants::HEADLESS_HTML.as_bytes().to_vec();
 
@@ -65,43 +69,17 @@ fn create_new_window_headless(
 		Ok(item) => item,
 	};
 
-	let protocol = webview.with_hotkeys_zoom(true).with_custom_protocol(
-		"wry".into(),
-		move |request| {
-			let path = request.uri().path();
-			let clean_path = &path[1..];
-			let content = content.clone();
-			let mut mime = mime_guess::from_path("index.html");
-
-			let content = if path == "/" {
-				content.into()
-			} else {
-				let decoded = decode_path(clean_path);
-				mime = mime_guess::from_path(decoded.clone());
-				match read(canonicalize(decoded).unwrap_or_default()) {
-					Err(_) => content.into(),
-					Ok(bytes) => bytes.into(),
-				}
-			};
-
-			let mimetype = mime
-				.first()
-				.map(|mime| mime.to_string())
-				.unwrap_or_else(|| "text/plain".to_string());
-
-			#[cfg(target_os = "windows")]
-			let headers = "https://wry.localhost".to_string();
-			#[cfg(not(target_os = "windows"))]
-			let headers = "wry://localhost".to_string();
-
-			Response::builder()
-				.header(CONTENT_TYPE, mimetype)
-				.header("Access-Control-Allow-Origin", headers)
-				.header("Accept-Encoding", "gzip, compress, br, deflate")
-				.body(content)
-				.map_err(Into::into)
-		},
-	);
+	let plotly_path = constants::PLOTLY_JS_PATH.trim_start_matches('/').to_string();
+	// Renderers only load the Plotly.js bundle, never local files
+	let mounts = vec![Mount::new(
+		"wry",
+		"/",
+		MountSource::Memory(HashMap::from([(plotly_path, MemoryAsset::new(plotly_js))])),
+	)];
+	let router = Router::new(mounts, content);
+	let protocol = webview
+		.with_hotkeys_zoom(true)
+		.with_custom_protocol("wry".into(), move |request| router.handle("wry", request));
 	let export_image = to_show.export_image.clone();
 	let _is_export = !export_image.is_empty();
 
@@ -125,12 +103,16 @@ fn create_new_window_headless(
 		window_id,
 		"".to_string(),
 		to_show.export_image,
+		ConflictPolicy::default(),
+		ExportTarget::default(),
+		Rc::new(NavigationPolicy::default()),
 		"".to_string().as_str(),
 		Some(true),
 		console,
 	);
 
-	return match init_view.with_devtools(console.active).with_url("wry://localhost") {
+	return match init_view.with_devtools(console.active).with_url(constants::HEADLESS_URL)
+	{
 		Err(error3) => return Err(error3.to_string()),
 		Ok(subitem) => match subitem.build() {
 			Err(error4) => return Err(error4.to_string()),
@@ -142,17 +124,23 @@ fn create_new_window_headless(
 /// Starts Main Runtime Loop and creates a new headless window on `WindowManager.start_headless()`
 ///
 /// # Description
-/// This function creates a new event loop and event loop proxy. We then create a new headless window and
-/// wait for data to be sent from Python. Once we receive data we send it to the webview to render the plot.
+/// This function creates a new event loop and event loop proxy. We then create a pool of headless windows and
+/// wait for data to be sent from Python. Each message is queued as a job and handed to the next idle
+/// webview to render the plot, so up to `renderers` exports run at the same time.
 ///
-/// We return a dictionary with the key `result` and base64 string encoded image.
+/// We return a dictionary with the key `result` and base64 string encoded image, along with the `id`
+/// of the request that produced it. Jobs that fail or run past their timeout return a dictionary with
+/// the key `error` instead, and a timed out renderer is reloaded before it takes another job.
 ///
 /// # Arguments
 /// * `console` - The ConsolePrinter struct to print log messages to the console
+/// * `options` - The number of headless webviews in the pool and the Plotly.js bundle they load
 ///
 /// # Returns
 /// * `Result<(), String>` - An error message or nothing
-pub fn start_headless(console: ConsolePrinter) -> Result<(), String> {
+pub fn start_headless(
+	console: ConsolePrinter, options: HeadlessOptions,
+) -> Result<(), String> {
 	#[cfg(wry_event_loop)]
 	let event_loop: EventLoop<UserEvent> =
 		EventLoopBuilder::<UserEvent>::with_user_event().build();
@@ -161,6 +149,7 @@ pub fn start_headless(console: ConsolePrinter) -> Result<(), String> {
 
 	let proxy = event_loop.create_proxy();
 	let mut webviews = HashMap::new();
+	let mut jobs = RenderQueue::new(options.render_timeout);
 	let mut listener_spawned = false;
 
 	event_loop.run(move |event, event_loop, control_flow| {
@@ -168,15 +157,33 @@ pub fn start_headless(console: ConsolePrinter) -> Result<(), String> {
 
 		if !listener_spawned {
 			console.debug("Starting listener thread");
+			console.send(
+				ClientEvent::HeadlessStarted {
+					renderers: options.renderers.max(1),
+					plotly_version: options.plotly_version(),
+				},
+				None,
+			);
 
-			let chart = ShowableHeadless::new("").unwrap_or_default();
-			match create_new_window_headless(chart, &event_loop, &proxy, console) {
-				Err(error) => console.error(&format!("Window Creation Error: {}", error)),
-
-				Ok(new_window) => {
-					webviews.insert(new_window.0, new_window.1);
-				}
-			};
+			for _ in 0..options.renderers.max(1) {
+				let chart = ShowableHeadless::default();
+				match create_new_window_headless(
+					chart,
+					options.plotly_js.clone(),
+					&event_loop,
+					&proxy,
+					console,
+				) {
+					Err(error) => console.error(&format!("Window Creation Error: {}", error)),
+
+					Ok(new_window) => {
+						webviews.insert(
+							new_window.0,
+							WindowState::new(new_window.1, None, WindowHandle::next()),
+						);
+					}
+				};
+			}
 
 			let proxy = proxy.clone();
 			std::thread::spawn(move || {
@@ -197,6 +204,19 @@ pub fn start_headless(console: ConsolePrinter) -> Result<(), String> {
 			listener_spawned = true;
 		}
 
-		handle_events(event, &mut webviews, &proxy, console.clone(), event_loop, true);
+		handle_events(
+			event,
+			&mut webviews,
+			&mut jobs,
+			&proxy,
+			console.clone(),
+			event_loop,
+			true,
+		);
+
+		*control_flow = match next_deadline(&webviews, &jobs) {
+			Some(deadline) => ControlFlow::WaitUntil(deadline),
+			None => ControlFlow::Wait,
+		};
 	});
 }
@@ -1,12 +1,19 @@
 #![warn(clippy::all, clippy::pedantic, clippy::nursery)]
 #![allow(clippy::missing_errors_doc, clippy::must_use_candidate)]
-use std::env;
+use std::{env, time::Duration};
 
+pub mod archive;
 pub mod constants;
 pub mod events;
 pub mod handlers;
 pub mod headless;
+pub mod navigation;
 pub mod pipe;
+pub mod protocol;
+pub mod relay;
+pub mod response;
+pub mod router;
+pub mod stream;
 pub mod structs;
 pub mod utils;
 pub mod window;
@@ -31,9 +38,11 @@ impl WindowManager {
 		}
 	}
 
-	pub fn start_headless(&self, debug: bool) -> Result<(), String> {
+	pub fn start_headless(
+		&self, debug: bool, options: structs::HeadlessOptions,
+	) -> Result<(), String> {
 		let console_printer = structs::ConsolePrinter::new(debug);
-		match headless::start_headless(console_printer) {
+		match headless::start_headless(console_printer, options) {
 			Err(error) => {
 				let error_str = format!("Error starting headless server: {}", error);
 				Err(error_str)
@@ -43,16 +52,53 @@ impl WindowManager {
 	}
 }
 
+/// Returns the value following `flag` in the command line arguments
+fn arg_value<'a>(args: &'a [String], flag: &str) -> Option<&'a str> {
+	args
+		.iter()
+		.position(|arg| arg == flag)
+		.and_then(|i| args.get(i + 1))
+		.map(String::as_str)
+}
+
 /// Starts the main runtime loop
 pub fn main() -> Result<(), String> {
 	let args: Vec<String> = env::args().collect();
 	let debug = args.contains(&"--debug".to_string());
 	let headless = args.contains(&"--headless".to_string());
+	// Answered before the other flags are read, so a bad `--plotly-js` doesn't block them
+	if args.contains(&"--print-schema".to_string()) {
+		let schema = protocol::ProtocolSchema::new();
+		println!("{}", serde_json::to_string_pretty(&schema).unwrap_or_default());
+		return Ok(());
+	}
+	let mut options = structs::HeadlessOptions::new();
+	if args.contains(&"--plotly-version".to_string()) {
+		println!("{}", serde_json::json!({ "plotly_version": options.plotly_version() }));
+		return Ok(());
+	}
+	if let Some(value) = arg_value(&args, "--renderers") {
+		match value.parse::<usize>() {
+			Ok(renderers) => options = options.with_renderers(renderers),
+			Err(_) => return Err(format!("Invalid value for --renderers: {}", value)),
+		}
+	}
+	if let Some(value) = arg_value(&args, "--render-timeout") {
+		match value.parse::<f64>() {
+			Ok(seconds) if seconds.is_finite() && seconds > 0.0 => {
+				options = options.with_render_timeout(Duration::from_secs_f64(seconds));
+			}
+			_ => return Err(format!("Invalid value for --render-timeout: {}", value)),
+		}
+	}
+	if let Some(path) = arg_value(&args, "--plotly-js") {
+		options = options.with_plotly_js(path)?;
+	}
 
 	let wm = WindowManager::new();
 
 	match headless {
-		true => match wm.start_headless(debug) {
+		true => match wm.start_headless(debug, options) {
 			Err(error) => Err(error),
 			Ok(_) => Ok(()),
 		},
@@ -0,0 +1,282 @@
+use crate::protocol::{ExternalAction, NavigationAction, NavigationRequest};
+use std::cell::RefCell;
+
+/// Urls windows don't open unless their policy says otherwise, e.g. the popups of Google widgets
+const DEFAULT_DENY: [&str; 1] = ["https://ogs.google.com*"];
+
+/// Whether a url matches a pattern, `*` matching any characters
+/// # Arguments
+/// * `pattern` - The pattern, e.g. `https://*.example.com/*`
+/// * `url` - The url to match
+/// # Returns
+/// * `bool` - Whether the whole url matches the pattern
+fn matches_pattern(pattern: &str, url: &str) -> bool {
+	let mut parts = pattern.split('*');
+	let mut rest = match url.strip_prefix(parts.next().unwrap_or_default()) {
+		Some(rest) => rest,
+		None => return false,
+	};
+	let parts: Vec<&str> = parts.collect();
+	let (last, middle) = match parts.split_last() {
+		Some(split) => split,
+		None => return rest.is_empty(),
+	};
+	for part in middle {
+		match rest.find(part) {
+			Some(index) => rest = &rest[index + part.len()..],
+			None => return false,
+		}
+	}
+	rest.ends_with(last)
+}
+
+/// Where a window may navigate to, and what happens to the links it can't open itself.
+pub struct NavigationPolicy {
+	/// Patterns of the urls the window may navigate to, every url when empty.
+	allow: Vec<String>,
+	/// Patterns of the urls never opened, taking precedence over `allow`.
+	deny: Vec<String>,
+	/// What happens to links opening a new window, or leading outside `allow`.
+	external: ExternalAction,
+	/// Whether the client is told about every navigation of the window.
+	pub notify: bool,
+	/// The custom protocols the window serves its own pages on, e.g. `wry`.
+	schemes: Vec<String>,
+	/// The origins of the relays the window loads its own pages from, when it proxies a server.
+	origins: RefCell<Vec<String>>,
+	/// A url the window navigates to next regardless of the policy, e.g. from a `navigate` command.
+	approved: RefCell<Option<String>>,
+}
+
+impl Default for NavigationPolicy {
+	fn default() -> Self {
+		Self {
+			allow: Vec::new(),
+			deny: DEFAULT_DENY.iter().map(ToString::to_string).collect(),
+			external: ExternalAction::default(),
+			notify: false,
+			schemes: vec!["wry".to_string()],
+			origins: RefCell::new(Vec::new()),
+			approved: RefCell::new(None),
+		}
+	}
+}
+
+impl NavigationPolicy {
+	pub fn from_request(request: NavigationRequest) -> Self {
+		let defaults = Self::default();
+		Self {
+			allow: request.allow.unwrap_or(defaults.allow),
+			deny: request.deny.unwrap_or(defaults.deny),
+			external: request.external.unwrap_or(defaults.external),
+			notify: true,
+			..defaults
+		}
+	}
+
+	pub fn with_schemes(mut self, schemes: Vec<String>) -> Self {
+		self.schemes = schemes;
+		self
+	}
+
+	/// Treats the pages of `origin` as the window's own, e.g. those of its relay
+	pub fn add_origin(&self, origin: &str) {
+		self.origins.borrow_mut().push(origin.to_string());
+	}
+
+	/// Lets the next navigation to `url` through, whatever the patterns say
+	pub fn approve(&self, url: &str) {
+		*self.approved.borrow_mut() = Some(url.to_string());
+	}
+
+	/// Decides what happens to a navigation of the window
+	/// # Arguments
+	/// * `url` - The url the window is about to load
+	/// # Returns
+	/// * `NavigationAction` - `Allow` to load it in the window, or what to do instead
+	pub fn navigate(&self, url: &str) -> NavigationAction {
+		// The webview may add a trailing `/` to the url it was asked to load
+		let approved = self.approved.borrow().as_ref().map_or(false, |approved| {
+			url.strip_prefix(approved.as_str()).map_or(false, |rest| matches!(rest, "" | "/"))
+		});
+		if approved {
+			self.approved.replace(None);
+			return NavigationAction::Allow;
+		}
+		if self.is_denied(url) {
+			return NavigationAction::Block;
+		}
+		if self.is_internal(url) {
+			return NavigationAction::Allow;
+		}
+		match self.allow.is_empty()
+			|| self.allow.iter().any(|pattern| matches_pattern(pattern, url))
+		{
+			true => NavigationAction::Allow,
+			false => self.external(url),
+		}
+	}
+
+	/// Decides what happens to a link of the window opening a new window
+	/// # Arguments
+	/// * `url` - The url of the link
+	/// # Returns
+	/// * `NavigationAction` - Where to open the link, if anywhere
+	pub fn new_window(&self, url: &str) -> NavigationAction {
+		match self.is_denied(url) {
+			true => NavigationAction::Block,
+			false => self.external(url),
+		}
+	}
+
+	/// Whether a url is a page of the window itself, served by its custom protocols
+	fn is_internal(&self, url: &str) -> bool {
+		// Empty documents and the `srcdoc` of iframes belong to the page that made them
+		if matches!(url, "about:blank" | "about:srcdoc") {
+			return true;
+		}
+		let served = self.origins.borrow().iter().any(|origin| {
+			url
+				.strip_prefix(origin.as_str())
+				.map_or(false, |rest| rest.is_empty() || rest.starts_with(['/', '?', '#']))
+		});
+		if served {
+			return true;
+		}
+		let scheme = url.split(':').next().unwrap_or_default();
+		// Webview2 serves custom protocols as `https://<scheme>.localhost`
+		let host =
+			url.strip_prefix("https://").and_then(|rest| rest.split(['/', '?', '#']).next());
+		self.schemes.iter().any(|custom| match cfg!(target_os = "windows") {
+			true => host.map_or(false, |host| host == format!("{}.localhost", custom)),
+			false => scheme == custom,
+		})
+	}
+
+	fn is_denied(&self, url: &str) -> bool {
+		self.deny.iter().any(|pattern| matches_pattern(pattern, url))
+	}
+
+	/// Applies `external` to a link, blocking the links it can't open
+	fn external(&self, url: &str) -> NavigationAction {
+		let is_web = url.starts_with("http://") || url.starts_with("https://");
+		match self.external {
+			ExternalAction::Window if is_web => NavigationAction::Window,
+			ExternalAction::Browser if is_web || url.starts_with("mailto:") => {
+				NavigationAction::Browser
+			}
+			_ => NavigationAction::Block,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn policy(allow: &[&str], external: ExternalAction) -> NavigationPolicy {
+		NavigationPolicy::from_request(NavigationRequest {
+			allow: Some(allow.iter().map(ToString::to_string).collect()),
+			deny: None,
+			external: Some(external),
+		})
+	}
+
+	/// The url of a page served by the window on a custom protocol
+	fn page(scheme: &str, path: &str) -> String {
+		match cfg!(target_os = "windows") {
+			true => format!("https://{}.localhost/{}", scheme, path),
+			false => format!("{}://localhost/{}", scheme, path),
+		}
+	}
+
+	#[test]
+	fn patterns_match_the_whole_url() {
+		assert!(matches_pattern("https://example.com", "https://example.com"));
+		assert!(!matches_pattern("https://example.com", "https://example.com/page"));
+		assert!(matches_pattern("https://*.example.com/*", "https://docs.example.com/a/b"));
+		assert!(!matches_pattern("https://*.example.com/*", "https://example.org/"));
+		assert!(matches_pattern("*", ""));
+	}
+
+	#[test]
+	fn patterns_with_overlapping_parts() {
+		assert!(matches_pattern("*ab*ab", "abab"));
+		assert!(!matches_pattern("*ab*ab", "aba"));
+		assert!(!matches_pattern("a*a", "a"));
+		assert!(matches_pattern("a*a", "aa"));
+		assert!(!matches_pattern("ab*b", "ab"));
+		assert!(matches_pattern("https://*/*", "https://a.com/"));
+		assert!(matches_pattern("*.com*.com", "a.com.com"));
+	}
+
+	#[test]
+	fn navigate_applies_the_policy() {
+		let policy = policy(&["https://docs.example.com/*"], ExternalAction::Browser);
+		assert_eq!(policy.navigate("https://docs.example.com/a"), NavigationAction::Allow);
+		assert_eq!(policy.navigate("https://example.org/"), NavigationAction::Browser);
+		assert_eq!(policy.navigate(&page("wry", "index.html")), NavigationAction::Allow);
+		assert_eq!(
+			policy.navigate("https://ogs.google.com/widget"),
+			NavigationAction::Block
+		);
+		assert_eq!(
+			policy.new_window("https://docs.example.com/a"),
+			NavigationAction::Browser
+		);
+	}
+
+	#[test]
+	fn only_the_custom_protocols_of_the_window_are_internal() {
+		let policy = policy(&["https://docs.example.com/*"], ExternalAction::Browser)
+			.with_schemes(vec!["wry".to_string(), "mem".to_string()]);
+		assert_eq!(policy.navigate(&page("mem", "app.js")), NavigationAction::Allow);
+		assert_eq!(policy.navigate(&page("other", "app.js")), NavigationAction::Block);
+		assert_eq!(policy.navigate("about:blank"), NavigationAction::Allow);
+		for url in [
+			"data:text/html,<h1>hi</h1>",
+			"javascript:alert(1)",
+			"blob:null/1",
+			"ftp://a.com/",
+		] {
+			assert_eq!(policy.navigate(url), NavigationAction::Block, "{}", url);
+		}
+	}
+
+	#[test]
+	fn pages_of_the_relay_are_internal() {
+		let policy = policy(&["https://docs.example.com/*"], ExternalAction::Browser);
+		policy.add_origin("http://127.0.0.1:4000");
+		for url in ["http://127.0.0.1:4000", "http://127.0.0.1:4000/app?x=1"] {
+			assert_eq!(policy.navigate(url), NavigationAction::Allow, "{}", url);
+		}
+		for url in ["http://127.0.0.1:40001/", "http://127.0.0.1:8501/"] {
+			assert_eq!(policy.navigate(url), NavigationAction::Browser, "{}", url);
+		}
+	}
+
+	#[test]
+	fn deny_is_checked_first() {
+		let policy = NavigationPolicy::from_request(NavigationRequest {
+			allow: None,
+			deny: Some(vec!["data:*".to_string(), page("wry", "admin*")]),
+			external: None,
+		});
+		assert_eq!(policy.navigate("data:text/html,<h1>hi</h1>"), NavigationAction::Block);
+		assert_eq!(policy.navigate("javascript:alert(1)"), NavigationAction::Allow);
+		assert_eq!(policy.navigate(&page("wry", "admin/users")), NavigationAction::Block);
+		assert_eq!(policy.navigate(&page("wry", "index.html")), NavigationAction::Allow);
+	}
+
+	#[test]
+	fn approvals_are_used_once() {
+		let policy = policy(&["https://docs.example.com/*"], ExternalAction::Block);
+		policy.approve("https://example.org");
+		assert_eq!(policy.navigate("https://example.org/"), NavigationAction::Allow);
+		assert_eq!(policy.navigate("https://example.org/"), NavigationAction::Block);
+
+		policy.approve("https://example.org/page");
+		assert_eq!(policy.navigate("https://example.org/other"), NavigationAction::Block);
+		assert_eq!(policy.navigate("https://example.org/page"), NavigationAction::Allow);
+	}
+}
@@ -1,37 +1,68 @@
-use tokio::io::{self, AsyncBufReadExt};
+use tokio::{
+	io::{self, AsyncBufReadExt},
+	time::{MissedTickBehavior, interval},
+};
 use wry::application::event_loop::EventLoopProxy;
 
-use crate::structs::UserEvent;
+use crate::{
+	protocol::WindowCommand,
+	stream::{STREAM_FRAME, StreamBuffer, StreamFrame},
+	structs::UserEvent,
+};
 
-pub async fn send_message(message: String, proxy: &EventLoopProxy<UserEvent>) {
-	match serde_json::from_str::<serde_json::Value>(&message.trim()) {
-		Ok(_) => {
-			proxy.send_event(UserEvent::NewMessageReceived(message)).unwrap_or_default();
-		}
-		Err(_) => {
-			proxy
-				.send_event(UserEvent::NewMessageReceived(
-					serde_json::json!({ "error": "Invalid JSON" }).to_string(),
-				))
-				.unwrap_or_default();
+/// Sends the points buffered from `stream` commands to the event loop, if any
+fn flush_streams(streams: &mut StreamBuffer, proxy: &EventLoopProxy<UserEvent>) {
+	if !streams.is_empty() {
+		proxy.send_event(UserEvent::StreamFrames(streams.take())).unwrap_or_default();
+	}
+}
+
+pub async fn send_message(
+	message: String, proxy: &EventLoopProxy<UserEvent>, streams: &mut StreamBuffer,
+) {
+	// Invalid messages are still forwarded, so the parser can reply with why they were rejected
+	let json = serde_json::from_str::<serde_json::Value>(&message.trim()).ok();
+	let request_id = json.as_ref().and_then(|json| json.get("id").cloned());
+	let cmd = json.as_ref().and_then(|json| json.get("cmd"));
+
+	if cmd.and_then(|cmd| cmd.as_str()) == Some("stream") {
+		if let Some(frame) =
+			WindowCommand::from_json(&message).ok().and_then(StreamFrame::new)
+		{
+			return streams.push(frame);
 		}
 	}
+
+	// Points streamed before this message must reach their window before it is handled
+	flush_streams(streams, proxy);
+	let event = match cmd {
+		Some(_) => UserEvent::CommandReceived(message, request_id),
+		None => UserEvent::NewMessageReceived(message, request_id),
+	};
+	proxy.send_event(event).unwrap_or_default();
 }
 
 pub async fn run_listener(
 	proxy: &EventLoopProxy<UserEvent>,
 ) -> Result<(), Box<dyn std::error::Error>> {
 	let stdin = io::stdin();
-	let mut reader = io::BufReader::new(stdin);
+	let mut lines = io::BufReader::new(stdin).lines();
+	let mut streams = StreamBuffer::default();
+	let mut frame = interval(STREAM_FRAME);
+	frame.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
 	loop {
-		let mut line = String::new();
-
-		// Read from stdin asynchronously
-		match reader.read_line(&mut line).await.expect("Failed to read from stdin") {
-			// No bytes read, so EOF has been reached
-			0 => return Ok(()),
-			_ => send_message(line, proxy).await,
+		tokio::select! {
+			// Read from stdin asynchronously
+			line = lines.next_line() => match line.expect("Failed to read from stdin") {
+				// EOF has been reached
+				None => {
+					flush_streams(&mut streams, proxy);
+					return Ok(());
+				}
+				Some(line) => send_message(line, proxy, &mut streams).await,
+			},
+			_ = frame.tick(), if !streams.is_empty() => flush_streams(&mut streams, proxy),
 		}
 	}
 }
@@ -0,0 +1,810 @@
+use schemars::{JsonSchema, schema::RootSchema, schema_for};
+use serde::{Deserialize, Deserializer, Serialize, de};
+use serde_json::{Map, Value};
+use std::{
+	collections::BTreeMap,
+	fmt,
+	path::PathBuf,
+	sync::atomic::{AtomicU64, Ordering},
+};
+
+/// A message read from stdin asking to show a window, or to export a figure when headless.
+///
+/// Parsing is strict: unknown keys and values of the wrong type are rejected,
+/// so typos like `widht` are reported back to the client instead of being ignored.
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ShowRequest {
+	/// Echoed back on every message the request causes.
+	pub id: Option<Value>,
+	/// HTML string, or path to an HTML file.
+	pub html: Option<String>,
+	pub title: Option<String>,
+	/// Path to a `png` icon for the window.
+	pub icon: Option<String>,
+	/// Data passed to the page as `window.json_data`.
+	pub json_data: Option<JsonData>,
+	pub height: Option<u32>,
+	pub width: Option<u32>,
+	pub download_path: Option<String>,
+	pub export_image: Option<String>,
+	/// What to do when a download would be saved over an existing file.
+	pub download_conflict: Option<ConflictPolicy>,
+	/// Where the image of `export_image` goes, a file by default.
+	pub export_to: Option<ExportTarget>,
+	/// Used as the handle of the window instead of a number, must be unique.
+	pub label: Option<String>,
+	/// Directories the page may load files from through `wry://`. Defaults to the
+	/// directory of the html file, and to none for html strings.
+	pub asset_roots: Option<Vec<String>>,
+	/// Files served from memory through `wry://`, by virtual path, so pages can load
+	/// scripts, stylesheets and images that don't exist on disk.
+	pub assets: Option<BTreeMap<String, AssetContent>>,
+	/// A `.zip` or `.tar.gz` archive served as the web root of the window. Its
+	/// `index.html` is shown when `html` is omitted, and for paths it doesn't have.
+	pub archive: Option<String>,
+	/// More paths of `wry://` or other custom protocols the page can load from.
+	pub mounts: Option<Vec<MountRequest>>,
+	/// A HTTP server on this machine shown as the page of the window, e.g. a Dash
+	/// or Streamlit app. Replaces `html`.
+	pub proxy: Option<ProxyRequest>,
+	/// Where the page may navigate to and where the links it can't open go. Without it
+	/// every url may be loaded and links opening a new window get a pywry window.
+	pub navigation: Option<NavigationRequest>,
+	/// Seconds a headless export may render before failing.
+	pub timeout: Option<f64>,
+}
+
+/// A message read from stdin asking to act on a window opened by an earlier request.
+/// `window` is the handle sent in the `window_created` event of that window.
+#[derive(Deserialize, JsonSchema)]
+#[serde(tag = "cmd", rename_all = "snake_case", deny_unknown_fields)]
+pub enum WindowCommand {
+	Close {
+		id: Option<Value>,
+		window: WindowHandle,
+	},
+	Focus {
+		id: Option<Value>,
+		window: WindowHandle,
+	},
+	Minimize {
+		id: Option<Value>,
+		window: WindowHandle,
+	},
+	Maximize {
+		id: Option<Value>,
+		window: WindowHandle,
+	},
+	/// Un-minimizes and un-maximizes the window.
+	Restore {
+		id: Option<Value>,
+		window: WindowHandle,
+	},
+	/// Moves the top left corner of the window, in logical pixels.
+	Move {
+		id: Option<Value>,
+		window: WindowHandle,
+		x: i32,
+		y: i32,
+	},
+	/// Resizes the content of the window, in logical pixels.
+	Resize {
+		id: Option<Value>,
+		window: WindowHandle,
+		width: u32,
+		height: u32,
+	},
+	SetTitle {
+		id: Option<Value>,
+		window: WindowHandle,
+		title: String,
+	},
+	SetAlwaysOnTop {
+		id: Option<Value>,
+		window: WindowHandle,
+		enabled: bool,
+	},
+	SetFullscreen {
+		id: Option<Value>,
+		window: WindowHandle,
+		enabled: bool,
+	},
+	OpenDevtools {
+		id: Option<Value>,
+		window: WindowHandle,
+	},
+	/// Updates the content of the window without recreating it, keeping its
+	/// geometry, scroll position and the zoom of its Plotly figure.
+	Update {
+		id: Option<Value>,
+		window: WindowHandle,
+		/// HTML string, or path to an HTML file, replacing the page.
+		html: Option<String>,
+		/// Replaces `window.json_data`.
+		json_data: Option<Value>,
+		/// Plotly figure passed to `Plotly.react` on the figure of the page.
+		figure: Option<Value>,
+	},
+	/// Runs a script in the window, replying with the value of its last expression,
+	/// awaited if it is a promise, or with the error it throws.
+	Eval {
+		id: Option<Value>,
+		window: WindowHandle,
+		script: String,
+		/// Seconds the script may run before failing, 10 by default.
+		timeout: Option<f64>,
+	},
+	/// Cancels a download of the window, named after the `download` of its `download_started` event.
+	CancelDownload {
+		id: Option<Value>,
+		window: WindowHandle,
+		download: u64,
+	},
+	/// Loads `url` in the window, even if its navigation policy doesn't allow it.
+	Navigate {
+		id: Option<Value>,
+		window: WindowHandle,
+		url: String,
+	},
+	/// Settles the promise returned by `window.pywry.call` in the window, resolving it
+	/// with `result`, or rejecting it with `error` when set.
+	Reply {
+		id: Option<Value>,
+		window: WindowHandle,
+		/// The `call` of the `call` event being replied to.
+		call: u64,
+		#[serde(default)]
+		result: Value,
+		error: Option<String>,
+	},
+	/// Appends points to the traces of the Plotly figure of the window with
+	/// `Plotly.extendTraces`. Commands arriving within a frame are sent together,
+	/// and only errors are replied to.
+	Stream {
+		id: Option<Value>,
+		window: WindowHandle,
+		/// Indices of the traces to extend.
+		traces: Vec<usize>,
+		/// The points to append to each trace by attribute, e.g. `{"x": [[1, 2]], "y": [[3, 4]]}`.
+		data: BTreeMap<String, Vec<Vec<Value>>>,
+		/// Keeps only the latest points of each trace.
+		max_points: Option<usize>,
+	},
+}
+
+impl WindowCommand {
+	/// Parses and validates a command read from stdin
+	/// # Arguments
+	/// * `raw_json` - The message as read from stdin
+	/// # Returns
+	/// * `Result<WindowCommand, String>` - The command or a description of what is wrong with it
+	pub fn from_json(raw_json: &str) -> Result<Self, String> {
+		let command: Self =
+			serde_json::from_str(raw_json).map_err(|error| error.to_string())?;
+
+		if let Self::Update { html: None, json_data: None, figure: None, .. } = command {
+			return Err(
+				"update expects at least one of html, json_data or figure".to_string(),
+			);
+		}
+		if let Self::Eval { timeout: Some(timeout), .. } = command {
+			if !timeout.is_finite() || timeout <= 0.0 {
+				return Err(format!(
+					"Invalid timeout: {}, expected a positive number",
+					timeout
+				));
+			}
+		}
+		if let Self::Stream { traces, data, .. } = &command {
+			if let Some((attribute, _)) =
+				data.iter().find(|(_, points)| points.len() != traces.len())
+			{
+				return Err(format!(
+					"Invalid data.{}: expected one array of points per trace in traces",
+					attribute
+				));
+			}
+		}
+
+		Ok(command)
+	}
+
+	/// The window the command acts on
+	pub fn window(&self) -> &WindowHandle {
+		match self {
+			Self::Close { window, .. }
+			| Self::Focus { window, .. }
+			| Self::Minimize { window, .. }
+			| Self::Maximize { window, .. }
+			| Self::Restore { window, .. }
+			| Self::Move { window, .. }
+			| Self::Resize { window, .. }
+			| Self::SetTitle { window, .. }
+			| Self::SetAlwaysOnTop { window, .. }
+			| Self::SetFullscreen { window, .. }
+			| Self::OpenDevtools { window, .. }
+			| Self::Update { window, .. }
+			| Self::Reply { window, .. }
+			| Self::Eval { window, .. }
+			| Self::CancelDownload { window, .. }
+			| Self::Navigate { window, .. }
+			| Self::Stream { window, .. } => window,
+		}
+	}
+
+	/// The name of the command, as sent in `cmd`
+	pub fn name(&self) -> &'static str {
+		match self {
+			Self::Close { .. } => "close",
+			Self::Focus { .. } => "focus",
+			Self::Minimize { .. } => "minimize",
+			Self::Maximize { .. } => "maximize",
+			Self::Restore { .. } => "restore",
+			Self::Move { .. } => "move",
+			Self::Resize { .. } => "resize",
+			Self::SetTitle { .. } => "set_title",
+			Self::SetAlwaysOnTop { .. } => "set_always_on_top",
+			Self::SetFullscreen { .. } => "set_fullscreen",
+			Self::OpenDevtools { .. } => "open_devtools",
+			Self::Update { .. } => "update",
+			Self::Reply { .. } => "reply",
+			Self::Eval { .. } => "eval",
+			Self::CancelDownload { .. } => "cancel_download",
+			Self::Navigate { .. } => "navigate",
+			Self::Stream { .. } => "stream",
+		}
+	}
+}
+
+/// What to do when a download would be saved over an existing file.
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ConflictPolicy {
+	/// Replaces the existing file.
+	#[default]
+	Overwrite,
+	/// Saves the download as `name (1).ext`, `name (2).ext`, ...
+	Rename,
+	/// Fails the download with a `download_failed` error.
+	Fail,
+}
+
+/// The content of a file of the `assets` map of a window.
+#[derive(Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum AssetContent {
+	/// Text, e.g. a script or stylesheet.
+	Text(String),
+	/// Base64 encoded binary content, e.g. an image.
+	Base64 { base64: String },
+}
+
+/// A path of a custom protocol of the window, served from a directory, from memory or
+/// by a local HTTP server. Exactly one of `directory`, `assets`, `archive` and `proxy` is set.
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct MountRequest {
+	/// Scheme the mount is served on, `wry` by default.
+	pub scheme: Option<String>,
+	/// Path the mount is served under, `/` by default.
+	pub path: Option<String>,
+	pub directory: Option<String>,
+	pub assets: Option<BTreeMap<String, AssetContent>>,
+	pub archive: Option<String>,
+	/// Base URL of a HTTP server on this machine the requests are forwarded to,
+	/// e.g. `http://127.0.0.1:8000`. Only allowed on the `wry` scheme.
+	pub proxy: Option<String>,
+}
+
+/// A local HTTP server served at the root of the window, so its pages can use `window.pywry`.
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyRequest {
+	/// Base URL of the server, e.g. `http://127.0.0.1:8501`.
+	pub url: String,
+	/// Headers set on the requests forwarded to the server, an empty value removes one.
+	pub request_headers: Option<BTreeMap<String, String>>,
+	/// Headers set on the responses of the server, an empty value removes one.
+	pub response_headers: Option<BTreeMap<String, String>>,
+	/// Whether the html pages of the server, including those shown in iframes, get
+	/// `window.pywry`. Defaults to true.
+	pub inject_scripts: Option<bool>,
+}
+
+/// Where a window may navigate to, by url pattern where `*` matches any characters.
+/// Pages on `wry://` and the other custom protocols of the window are allowed unless denied.
+#[derive(Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields)]
+pub struct NavigationRequest {
+	/// Urls the page may navigate to, every url by default. Others are `external`.
+	pub allow: Option<Vec<String>>,
+	/// Urls never opened, taking precedence over `allow`.
+	pub deny: Option<Vec<String>>,
+	/// What happens to links opening a new window, or leading outside `allow`.
+	pub external: Option<ExternalAction>,
+}
+
+/// What a window does with the links it doesn't open itself.
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ExternalAction {
+	/// Opens http and https links in a new pywry window.
+	#[default]
+	Window,
+	/// Opens http, https and mailto links with the default browser of the system.
+	Browser,
+	Block,
+}
+
+/// What a window did with a url it navigated to or opened.
+#[derive(Serialize, JsonSchema, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum NavigationAction {
+	/// Loaded it in the window.
+	Allow,
+	/// Opened it in a new pywry window.
+	Window,
+	/// Opened it with the default browser of the system.
+	Browser,
+	Block,
+}
+
+/// Where an export window puts the image it exports.
+#[derive(Deserialize, JsonSchema, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportTarget {
+	/// Saves the image to `export_image`.
+	#[default]
+	File,
+	/// Replies to the request with the image, base64 encoded, without saving it.
+	/// `export_image` only names the file.
+	Memory,
+}
+
+/// Identifies a window in the messages exchanged with the client. Windows get
+/// increasing numbers, unless the request that opened them set a `label`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, PartialEq, Eq, Debug)]
+#[serde(untagged)]
+pub enum WindowHandle {
+	Number(u64),
+	Label(String),
+}
+
+static NEXT_WINDOW_HANDLE: AtomicU64 = AtomicU64::new(1);
+
+impl WindowHandle {
+	/// Returns a number that was never used as a handle before
+	pub fn next() -> Self {
+		Self::Number(NEXT_WINDOW_HANDLE.fetch_add(1, Ordering::Relaxed))
+	}
+}
+
+impl fmt::Display for WindowHandle {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Number(number) => write!(f, "{}", number),
+			Self::Label(label) => write!(f, "{:?}", label),
+		}
+	}
+}
+
+/// The messages the backend reads from stdin. Messages with a `cmd` key are
+/// window commands, any other message shows a window.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+pub enum Incoming {
+	Show(ShowRequest),
+	Command(WindowCommand),
+}
+
+/// The `json_data` of a show message, passed to the page as `window.json_data`.
+#[derive(JsonSchema)]
+#[serde(untagged)]
+pub enum JsonData {
+	/// An object, whose keys described by `JsonDataOptions` are read by pywry too.
+	Object(JsonDataObject),
+	/// Any other value, e.g. a JSON string, passed to the page untouched.
+	Other(Value),
+}
+
+impl<'de> Deserialize<'de> for JsonData {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		// Not derived, an untagged enum would take an object with invalid options as `Other`
+		match Value::deserialize(deserializer)? {
+			Value::Object(object) => JsonDataObject::deserialize(Value::Object(object))
+				.map(Self::Object)
+				.map_err(|error| de::Error::custom(format!("Invalid json_data: {}", error))),
+			other => Ok(Self::Other(other)),
+		}
+	}
+}
+
+impl JsonData {
+	/// The keys pywry reads, when `json_data` is an object
+	pub fn options(&self) -> Option<&JsonDataOptions> {
+		match self {
+			Self::Object(object) => Some(&object.options),
+			Self::Other(_) => None,
+		}
+	}
+
+	/// Splits `json_data` into the value passed to the page and the keys pywry reads
+	pub fn into_parts(self) -> (Value, JsonDataOptions) {
+		match self {
+			Self::Object(object) => {
+				(serde_json::to_value(&object).unwrap_or_default(), object.options)
+			}
+			Self::Other(value) => (value, JsonDataOptions::default()),
+		}
+	}
+}
+
+/// A `json_data` object, the keys pywry reads along with any others.
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+pub struct JsonDataObject {
+	#[serde(flatten)]
+	pub options: JsonDataOptions,
+	/// Keys pywry doesn't read, passed to the page untouched.
+	#[serde(flatten)]
+	pub extra: Map<String, Value>,
+}
+
+/// Keys of `json_data` used by pywry.
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+pub struct JsonDataOptions {
+	/// `dark` gives the window a dark background, any other theme a light one.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub theme: Option<String>,
+	/// URL to load instead of the `html` content.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub url: Option<String>,
+	/// JavaScript code to be injected when the webview is ready.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub init_script: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub layout: Option<FigureLayout>,
+	/// Scale of a headless export.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub scale: Option<f64>,
+	/// Image format of a headless export.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub format: Option<ImageFormat>,
+	/// Plotly events of the figures in the page forwarded to the client as `plotly` events.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub plotly_events: Option<Vec<PlotlyEventName>>,
+	/// Forwards console messages of this level and above, and uncaught errors,
+	/// to the client as `console` events.
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub forward_console: Option<ConsoleLevel>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum ConsoleLevel {
+	Log,
+	Info,
+	Warn,
+	/// Also used for uncaught errors and unhandled promise rejections.
+	Error,
+}
+
+/// The Plotly events that can be forwarded to the client, named after
+/// `plotly_click`, `plotly_selected`, `plotly_relayout` and `plotly_hover`.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum PlotlyEventName {
+	Click,
+	Selected,
+	Relayout,
+	/// Throttled to one event every 100ms.
+	Hover,
+}
+
+/// The size of a Plotly figure, used to size its window or export.
+#[derive(Serialize, Deserialize, JsonSchema, Default)]
+pub struct FigureLayout {
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub width: Option<f64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub height: Option<f64>,
+	/// The rest of the Plotly layout, passed to the page untouched.
+	#[serde(flatten)]
+	pub extra: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageFormat {
+	#[default]
+	Png,
+	#[serde(alias = "jpg")]
+	Jpeg,
+	Webp,
+	Svg,
+}
+
+impl ImageFormat {
+	/// The format name `Plotly.toImage` expects
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ImageFormat::Png => "png",
+			ImageFormat::Jpeg => "jpeg",
+			ImageFormat::Webp => "webp",
+			ImageFormat::Svg => "svg",
+		}
+	}
+}
+
+impl ShowRequest {
+	/// Parses and validates a message read from stdin
+	/// # Arguments
+	/// * `raw_json` - The message as read from stdin
+	/// # Returns
+	/// * `Result<ShowRequest, String>` - The request or a description of what is wrong with it
+	pub fn from_json(raw_json: &str) -> Result<Self, String> {
+		let request: Self =
+			serde_json::from_str(raw_json).map_err(|error| error.to_string())?;
+
+		let options = request.json_data.as_ref().and_then(JsonData::options);
+		let layout = options.and_then(|options| options.layout.as_ref());
+		for (key, value) in [
+			("scale", options.and_then(|options| options.scale)),
+			("layout.width", layout.and_then(|layout| layout.width)),
+			("layout.height", layout.and_then(|layout| layout.height)),
+		] {
+			if let Some(value) = value.filter(|value| !value.is_finite() || *value <= 0.0) {
+				return Err(format!(
+					"Invalid json_data.{}: {}, expected a positive number",
+					key, value
+				));
+			}
+		}
+
+		if request.export_to == Some(ExportTarget::Memory) && request.export_image.is_none()
+		{
+			return Err("export_to memory expects an export_image".to_string());
+		}
+
+		if request.proxy.is_some() && (request.html.is_some() || request.archive.is_some())
+		{
+			return Err(
+				"proxy replaces html and archive, expected only one of them".to_string(),
+			);
+		}
+
+		if let Some(timeout) = request.timeout {
+			if !timeout.is_finite() || timeout <= 0.0 {
+				return Err(format!(
+					"Invalid timeout: {}, expected a positive number",
+					timeout
+				));
+			}
+		}
+
+		Ok(request)
+	}
+}
+
+/// Every message written to stdout, along with the `id` of the request that caused it.
+#[derive(Serialize, JsonSchema)]
+pub struct Outgoing<'a, T> {
+	#[serde(flatten)]
+	pub message: T,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub id: Option<&'a Value>,
+}
+
+/// The messages the backend writes to stdout.
+#[derive(Serialize, JsonSchema)]
+#[serde(untagged)]
+pub enum OutgoingMessage {
+	Result(ResultReply),
+	Ack(AckReply),
+	Error(ErrorReply),
+	Event(ClientEvent),
+	Log(LogMessage),
+}
+
+/// A result sent by a page with `window.pywry.result`, an exported image,
+/// or the value of a script run by an `eval` command.
+#[derive(Serialize, JsonSchema)]
+pub struct ResultReply {
+	pub result: Value,
+}
+
+/// A window command that was carried out, named after its `cmd`.
+#[derive(Serialize, JsonSchema)]
+pub struct AckReply {
+	pub ack: String,
+}
+
+/// A request that failed.
+#[derive(Serialize, JsonSchema)]
+pub struct ErrorReply {
+	pub error: ErrorInfo,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema)]
+pub struct ErrorInfo {
+	/// A stable identifier for the kind of failure, e.g. `invalid_request`.
+	pub code: String,
+	pub message: String,
+	/// The JavaScript stack trace, when the error was thrown by a page.
+	#[serde(default)]
+	pub stack: Option<String>,
+}
+
+impl ErrorReply {
+	pub fn new(code: &str, message: &str) -> Self {
+		Self {
+			error: ErrorInfo {
+				code: code.to_string(),
+				message: message.to_string(),
+				stack: None,
+			},
+		}
+	}
+}
+
+/// Something that happened to a window, or to the backend itself.
+#[derive(Serialize, JsonSchema)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ClientEvent {
+	WindowCreated {
+		window: WindowHandle,
+	},
+	WindowClosed {
+		window: WindowHandle,
+	},
+	WindowResized {
+		window: WindowHandle,
+		geometry: WindowGeometry,
+	},
+	WindowMoved {
+		window: WindowHandle,
+		geometry: WindowGeometry,
+	},
+	WindowFocused {
+		window: WindowHandle,
+	},
+	WindowBlurred {
+		window: WindowHandle,
+	},
+	/// A page started a download, `download` identifies it in the following events.
+	DownloadStarted {
+		download: u64,
+		path: PathBuf,
+	},
+	/// Bytes of a download received so far, sent for downloads pywry transfers itself.
+	/// Downloads the webview saves, e.g. from URLs, have no progress.
+	DownloadProgress {
+		download: u64,
+		received: u64,
+		total: Option<u64>,
+	},
+	/// A download was saved to `path`, along with its size in bytes and hex encoded SHA-256.
+	DownloadComplete {
+		download: u64,
+		path: PathBuf,
+		size: u64,
+		sha256: String,
+	},
+	DownloadCancelled {
+		download: u64,
+	},
+	HeadlessStarted {
+		renderers: usize,
+		plotly_version: String,
+	},
+	/// A page called `window.pywry.call(name, args)`, answered with a `reply` command.
+	Call {
+		window: WindowHandle,
+		call: u64,
+		name: String,
+		args: Value,
+	},
+	/// A console message or uncaught error of the page, opted in with `json_data.forward_console`.
+	Console {
+		window: WindowHandle,
+		level: ConsoleLevel,
+		message: String,
+		/// Where an uncaught error was thrown, as reported by the page. Null for messages
+		/// logged through `console`.
+		source: Option<String>,
+		line: Option<u32>,
+		column: Option<u32>,
+	},
+	/// A Plotly event of a figure in the window, opted in with `json_data.plotly_events`.
+	Plotly {
+		window: WindowHandle,
+		name: PlotlyEventName,
+		data: Value,
+	},
+	/// The page requested a file outside the asset roots of the window, and got a 403.
+	AssetDenied {
+		window: WindowHandle,
+		path: String,
+	},
+	/// The page navigated to or opened `url`, sent for windows with a `navigation` policy.
+	/// A blocked url can still be loaded with a `navigate` command.
+	Navigation {
+		window: WindowHandle,
+		url: String,
+		new_window: bool,
+		action: NavigationAction,
+	},
+}
+
+/// A console message or uncaught error of a page, as posted to the IPC handler.
+#[derive(Deserialize)]
+pub struct ConsoleRecord {
+	pub level: ConsoleLevel,
+	pub message: String,
+	pub source: Option<String>,
+	pub line: Option<u32>,
+	pub column: Option<u32>,
+}
+
+/// A Plotly event of a figure, as posted to the IPC handler.
+#[derive(Deserialize)]
+pub struct PlotlyEventMessage {
+	pub name: PlotlyEventName,
+	#[serde(default)]
+	pub data: Value,
+}
+
+/// The outcome of a script run by an `eval` command, as posted to the IPC handler.
+#[derive(Deserialize)]
+pub struct EvalOutcome {
+	pub eval: u64,
+	#[serde(default)]
+	pub result: Value,
+	pub error: Option<ErrorInfo>,
+}
+
+/// A call made by a page with `window.pywry.call`, as posted to the IPC handler.
+#[derive(Deserialize)]
+pub struct PageCall {
+	pub call: u64,
+	pub name: String,
+	#[serde(default)]
+	pub args: Value,
+}
+
+/// The position of the top left corner of a window and the size of its content,
+/// in logical pixels like the `move` and `resize` commands.
+#[derive(Clone, Serialize, JsonSchema)]
+pub struct WindowGeometry {
+	pub x: i32,
+	pub y: i32,
+	pub width: u32,
+	pub height: u32,
+}
+
+/// Log lines printed by `ConsolePrinter`, e.g. `{"debug": "..."}`.
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum LogMessage {
+	Debug(String),
+	Info(String),
+	Error(String),
+}
+
+/// The schemas of the messages read from stdin and written to stdout
+#[derive(Serialize)]
+pub struct ProtocolSchema {
+	pub inbound: RootSchema,
+	pub outbound: RootSchema,
+}
+
+impl ProtocolSchema {
+	/// Generates the schemas from the types used to parse and write messages,
+	/// so they always match what the backend accepts and sends
+	pub fn new() -> Self {
+		Self {
+			inbound: schema_for!(Incoming),
+			outbound: schema_for!(Outgoing<'static, OutgoingMessage>),
+		}
+	}
+}
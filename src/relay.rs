@@ -0,0 +1,599 @@
+use crate::{
+	response::ProtocolResponse,
+	router::{ProxyTarget, Route, Router, inject_script},
+};
+use std::{
+	io::{self, BufRead, BufReader, Read, Write},
+	net::{Shutdown, TcpListener, TcpStream},
+	sync::{
+		Arc,
+		atomic::{AtomicBool, Ordering},
+	},
+	thread,
+};
+use wry::http::{
+	HeaderValue, Method, Request, StatusCode, Version,
+	header::{
+		CONNECTION, CONTENT_LENGTH, COOKIE, HOST, LOCATION, SET_COOKIE, TRANSFER_ENCODING,
+		UPGRADE,
+	},
+};
+
+/// Longest request line and headers accepted, in bytes.
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+
+/// Largest request body accepted, in bytes.
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Query parameter of the first page load carrying the token of the relay, and name of
+/// the cookie it is traded for.
+const TOKEN_PARAMETER: &str = "pywry_relay";
+
+/// A HTTP server on the loopback interface serving the `wry` mounts of a window that
+/// proxies a local server, which the window loads its pages from instead of `wry://`.
+///
+/// Custom protocol handlers run on the event loop thread and wry 0.31 has no asynchronous
+/// ones, so a slow server would hold up every window. The relay answers each connection
+/// on a thread of its own instead, which also lets long polls, streamed responses and
+/// WebSockets through. Only requests carrying the cookie of the window are answered.
+pub struct Relay {
+	port: u16,
+	token: String,
+	/// Tells the listening thread to stop once the window is closed.
+	stopped: Arc<AtomicBool>,
+}
+
+/// What the threads of a relay share.
+struct Context {
+	router: Arc<Router>,
+	port: u16,
+	token: String,
+}
+
+impl Relay {
+	/// Starts a relay serving the `wry` mounts of a window
+	/// # Arguments
+	/// * `router` - The router of the window
+	/// # Returns
+	/// * `Result<Relay, String>` - The relay, or why it can't listen on the loopback interface
+	pub fn start(router: Arc<Router>) -> Result<Self, String> {
+		let error = |error: io::Error| format!("Can't start the proxy relay: {}", error);
+		let listener = TcpListener::bind(("127.0.0.1", 0)).map_err(error)?;
+		let port = listener.local_addr().map_err(error)?.port();
+		let token = format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>());
+		let stopped = Arc::new(AtomicBool::new(false));
+
+		let context = Arc::new(Context { router, port, token: token.clone() });
+		let accepting = stopped.clone();
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				if accepting.load(Ordering::Relaxed) {
+					break;
+				}
+				if let Ok(stream) = stream {
+					let context = context.clone();
+					thread::spawn(move || serve_connection(stream, &context));
+				}
+			}
+		});
+		Ok(Self { port, token, stopped })
+	}
+
+	/// Returns the origin of the pages the relay serves, e.g. `http://127.0.0.1:41234`
+	pub fn origin(&self) -> String {
+		format!("http://127.0.0.1:{}", self.port)
+	}
+
+	/// Returns the url the window opens a page of its `wry` mounts with. It carries the
+	/// token of the relay, which answers it with a cookie and the same url without it
+	/// # Arguments
+	/// * `page` - The url of the page on `wry://`, e.g. `wry://localhost/index.html`
+	/// # Returns
+	/// * `String` - The url of the page on the relay
+	pub fn url(&self, page: &str) -> String {
+		let rest = page.strip_prefix("wry://").unwrap_or(page);
+		let path = rest.find(['/', '?']).map_or("/", |start| &rest[start..]);
+		let path = path.split('#').next().unwrap_or_default();
+		let separator = if path.contains('?') { '&' } else { '?' };
+		format!("{}{}{}{}={}", self.origin(), path, separator, TOKEN_PARAMETER, self.token)
+	}
+}
+
+impl Drop for Relay {
+	/// Stops accepting connections once the window is closed, those still open end
+	/// along with its webview
+	fn drop(&mut self) {
+		self.stopped.store(true, Ordering::Relaxed);
+		// Wakes the listening thread up so it sees the flag
+		let _ = TcpStream::connect(("127.0.0.1", self.port));
+	}
+}
+
+impl Context {
+	fn origin(&self) -> String {
+		format!("http://127.0.0.1:{}", self.port)
+	}
+
+	fn cookie(&self) -> String {
+		format!("{}_{}={}", TOKEN_PARAMETER, self.port, self.token)
+	}
+
+	/// Whether a request was sent to the address of the relay, rather than to a name
+	/// resolving to it, like a page of another site rebinding its domain would
+	fn is_addressed(&self, request: &Request<Vec<u8>>) -> bool {
+		let host = request.headers().get(HOST).and_then(|value| value.to_str().ok());
+		host == Some(&format!("127.0.0.1:{}", self.port))
+	}
+
+	fn cookies<'a>(
+		&self, request: &'a Request<Vec<u8>>,
+	) -> impl Iterator<Item = &'a str> {
+		request
+			.headers()
+			.get_all(COOKIE)
+			.iter()
+			.filter_map(|value| value.to_str().ok())
+			.flat_map(|value| value.split(';'))
+			.map(str::trim)
+			.filter(|cookie| !cookie.is_empty())
+	}
+
+	/// Whether a request comes from the window, carrying the cookie of the relay
+	fn is_authorized(&self, request: &Request<Vec<u8>>) -> bool {
+		let cookie = self.cookie();
+		self.is_addressed(request) && self.cookies(request).any(|sent| sent == cookie)
+	}
+
+	/// Returns the url without its token for a request carrying the token of the relay
+	/// in its query, or `None` when it doesn't
+	fn login(&self, request: &Request<Vec<u8>>) -> Option<String> {
+		let query = request.uri().query()?;
+		let token = format!("{}={}", TOKEN_PARAMETER, self.token);
+		if !self.is_addressed(request) || !query.split('&').any(|pair| pair == token) {
+			return None;
+		}
+		let rest: Vec<&str> = query.split('&').filter(|pair| *pair != token).collect();
+		let path = request.uri().path();
+		Some(match rest.is_empty() {
+			true => path.to_string(),
+			false => format!("{}?{}", path, rest.join("&")),
+		})
+	}
+
+	/// Removes the cookie of the relay from a request, the server has no use for it
+	fn strip_cookie(&self, request: &mut Request<Vec<u8>>) {
+		let cookie = self.cookie();
+		let rest: Vec<&str> =
+			self.cookies(request).filter(|sent| *sent != cookie).collect();
+		let value =
+			HeaderValue::from_str(&rest.join("; ")).ok().filter(|_| !rest.is_empty());
+		let headers = request.headers_mut();
+		headers.remove(COOKIE);
+		if let Some(value) = value {
+			headers.insert(COOKIE, value);
+		}
+	}
+}
+
+/// Answers the requests of a connection of the webview until either side closes it
+/// # Arguments
+/// * `stream` - The connection
+/// * `context` - What the threads of the relay share
+/// # Returns
+/// * `io::Result<()>` - Nothing, or why the connection broke
+fn serve_connection(stream: TcpStream, context: &Context) -> io::Result<()> {
+	stream.set_nodelay(true)?;
+	let mut reader = BufReader::new(stream.try_clone()?);
+	let mut writer = stream;
+	loop {
+		let mut request = match read_request(&mut reader) {
+			Ok(Some(request)) => request,
+			Ok(None) => return Ok(()),
+			Err(status) => return write_status(&mut writer, status, false),
+		};
+		let keep_alive = keeps_alive(&request);
+
+		if let Some(location) = context.login(&request) {
+			let cookie = format!("{}; Path=/; HttpOnly; SameSite=Lax", context.cookie());
+			let headers = [
+				(LOCATION.to_string(), location),
+				(SET_COOKIE.to_string(), cookie),
+				(CONTENT_LENGTH.to_string(), "0".to_string()),
+			];
+			write_head(&mut writer, StatusCode::SEE_OTHER, &headers, keep_alive)?;
+		} else if !context.is_authorized(&request) {
+			write_status(&mut writer, StatusCode::FORBIDDEN, keep_alive)?;
+		} else {
+			context.strip_cookie(&mut request);
+			match context.router.route("wry", &request) {
+				Route::Proxy { target, path, .. } if is_upgrade(&request) => {
+					return tunnel(reader, writer, target, &request, path, &context.origin());
+				}
+				Route::Proxy { target, prefix, path } => {
+					let origin = context.origin();
+					let route = (target, prefix, path);
+					write_proxied(&mut writer, route, &request, &origin, keep_alive)?;
+				}
+				Route::Response(response) => {
+					write_response(&mut writer, response, &request, keep_alive)?;
+				}
+			}
+		}
+		if !keep_alive {
+			return Ok(());
+		}
+	}
+}
+
+/// Reads the next request of a connection
+/// # Arguments
+/// * `reader` - The connection
+/// # Returns
+/// * `Result<Option<Request<Vec<u8>>>, StatusCode>` - The request, `None` once the webview
+///   closed the connection, or the status to answer a malformed request with
+fn read_request(
+	reader: &mut impl BufRead,
+) -> Result<Option<Request<Vec<u8>>>, StatusCode> {
+	let mut lines = Vec::new();
+	let mut size = 0;
+	loop {
+		let mut line = String::new();
+		let read = reader
+			.by_ref()
+			.take((MAX_HEAD_SIZE + 1 - size) as u64)
+			.read_line(&mut line)
+			.map_err(|_| StatusCode::BAD_REQUEST)?;
+		size += read;
+		if size > MAX_HEAD_SIZE {
+			return Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+		}
+		if read == 0 {
+			return match lines.is_empty() {
+				true => Ok(None),
+				false => Err(StatusCode::BAD_REQUEST),
+			};
+		}
+		let line = line.trim_end_matches(['\r', '\n']).to_string();
+		match (line.is_empty(), lines.is_empty()) {
+			// Clients may send empty lines between the requests of a connection
+			(true, true) => continue,
+			(true, false) => break,
+			(false, _) => lines.push(line),
+		}
+	}
+
+	let parts: Vec<&str> = lines[0].split(' ').collect();
+	let (method, target, version) = match parts[..] {
+		[method, target, version] if target.starts_with('/') => (method, target, version),
+		_ => return Err(StatusCode::BAD_REQUEST),
+	};
+	let version = match version {
+		"HTTP/1.1" => Version::HTTP_11,
+		"HTTP/1.0" => Version::HTTP_10,
+		_ => return Err(StatusCode::HTTP_VERSION_NOT_SUPPORTED),
+	};
+	let mut builder = Request::builder().method(method).uri(target).version(version);
+	for line in &lines[1..] {
+		let (name, value) = line.split_once(':').ok_or(StatusCode::BAD_REQUEST)?;
+		builder = builder.header(name.trim(), value.trim());
+	}
+
+	let headers = builder.headers_ref().ok_or(StatusCode::BAD_REQUEST)?;
+	let chunked = headers.get_all(TRANSFER_ENCODING).iter().any(|value| {
+		value.to_str().map_or(false, |value| value.to_ascii_lowercase().contains("chunked"))
+	});
+	let length = match headers.get(CONTENT_LENGTH) {
+		Some(value) => value
+			.to_str()
+			.ok()
+			.and_then(|value| value.trim().parse::<usize>().ok())
+			.ok_or(StatusCode::BAD_REQUEST)?,
+		None => 0,
+	};
+	let body = match chunked {
+		true => read_chunked(reader)?,
+		false if length > MAX_BODY_SIZE => return Err(StatusCode::PAYLOAD_TOO_LARGE),
+		false => {
+			let mut body = vec![0; length];
+			reader.read_exact(&mut body).map_err(|_| StatusCode::BAD_REQUEST)?;
+			body
+		}
+	};
+	builder.body(body).map(Some).map_err(|_| StatusCode::BAD_REQUEST)
+}
+
+/// Reads a body sent in chunks, as streamed uploads are
+fn read_chunked(reader: &mut impl BufRead) -> Result<Vec<u8>, StatusCode> {
+	let read_line = |reader: &mut dyn BufRead| {
+		let mut line = String::new();
+		reader.take(1024).read_line(&mut line).map_err(|_| StatusCode::BAD_REQUEST)?;
+		Ok::<String, StatusCode>(line)
+	};
+	let mut body = Vec::new();
+	loop {
+		let line = read_line(reader)?;
+		let size = line.trim().split(';').next().unwrap_or_default();
+		let size = usize::from_str_radix(size, 16).map_err(|_| StatusCode::BAD_REQUEST)?;
+		if body.len() + size > MAX_BODY_SIZE {
+			return Err(StatusCode::PAYLOAD_TOO_LARGE);
+		}
+		if size == 0 {
+			// Trailers are dropped
+			while !read_line(reader)?.trim().is_empty() {}
+			return Ok(body);
+		}
+		let start = body.len();
+		body.resize(start + size, 0);
+		reader.read_exact(&mut body[start..]).map_err(|_| StatusCode::BAD_REQUEST)?;
+		read_line(reader)?;
+	}
+}
+
+fn keeps_alive(request: &Request<Vec<u8>>) -> bool {
+	let close = request.headers().get_all(CONNECTION).iter().any(|value| {
+		value.to_str().map_or(false, |value| {
+			value.split(',').any(|token| token.trim().eq_ignore_ascii_case("close"))
+		})
+	});
+	request.version() == Version::HTTP_11 && !close
+}
+
+fn is_upgrade(request: &Request<Vec<u8>>) -> bool {
+	let upgrade = request.headers().get(UPGRADE).and_then(|value| value.to_str().ok());
+	upgrade.map_or(false, |upgrade| upgrade.eq_ignore_ascii_case("websocket"))
+}
+
+/// Whether responses with a status have a body, even an empty one
+fn has_body(status: StatusCode) -> bool {
+	!status.is_informational()
+		&& status != StatusCode::NO_CONTENT
+		&& status != StatusCode::NOT_MODIFIED
+}
+
+/// Writes the status line and headers of a response, skipping headers that would
+/// break out of their line
+fn write_head(
+	writer: &mut impl Write, status: StatusCode, headers: &[(String, String)],
+	keep_alive: bool,
+) -> io::Result<()> {
+	let mut head = format!(
+		"HTTP/1.1 {} {}\r\n",
+		status.as_u16(),
+		status.canonical_reason().unwrap_or_default()
+	);
+	for (name, value) in headers {
+		if !name.contains(['\r', '\n']) && !value.contains(['\r', '\n']) {
+			head.push_str(&format!("{}: {}\r\n", name, value));
+		}
+	}
+	if !keep_alive {
+		head.push_str("Connection: close\r\n");
+	}
+	head.push_str("\r\n");
+	writer.write_all(head.as_bytes())?;
+	writer.flush()
+}
+
+/// Answers with an empty response, e.g. a `403` or `502`
+fn write_status(
+	writer: &mut impl Write, status: StatusCode, keep_alive: bool,
+) -> io::Result<()> {
+	let headers = [(CONTENT_LENGTH.to_string(), "0".to_string())];
+	write_head(writer, status, &headers, keep_alive)
+}
+
+/// Answers with a response of the mounts of the window served from memory or a directory
+fn write_response(
+	writer: &mut impl Write, response: ProtocolResponse, request: &Request<Vec<u8>>,
+	keep_alive: bool,
+) -> io::Result<()> {
+	let response = match response {
+		Ok(response) => response,
+		Err(_) => {
+			return write_status(writer, StatusCode::INTERNAL_SERVER_ERROR, keep_alive);
+		}
+	};
+	let status = response.status();
+	let mut headers: Vec<(String, String)> = response
+		.headers()
+		.iter()
+		.filter(|(name, _)| ![CONTENT_LENGTH, CONNECTION, TRANSFER_ENCODING].contains(name))
+		.filter_map(|(name, value)| {
+			Some((name.to_string(), value.to_str().ok()?.to_string()))
+		})
+		.collect();
+	if has_body(status) {
+		headers.push((CONTENT_LENGTH.to_string(), response.body().len().to_string()));
+	}
+	write_head(writer, status, &headers, keep_alive)?;
+	if has_body(status) && request.method() != Method::HEAD {
+		writer.write_all(response.body())?;
+	}
+	writer.flush()
+}
+
+/// Forwards a request to the server of a proxy mount and answers with its response. Html
+/// pages are read whole to add the script of the mount, other bodies are passed along in
+/// chunks as the server writes them, so streamed responses and server-sent events work
+/// # Arguments
+/// * `writer` - The connection of the webview
+/// * `route` - The server, the path of the mount and the path of the request relative to it
+/// * `request` - The request of the webview
+/// * `origin` - The origin of the relay
+/// * `keep_alive` - Whether the connection serves more requests after this one
+/// # Returns
+/// * `io::Result<()>` - Nothing, or why the connection broke
+fn write_proxied(
+	writer: &mut impl Write, route: (&ProxyTarget, &str, &str),
+	request: &Request<Vec<u8>>, origin: &str, keep_alive: bool,
+) -> io::Result<()> {
+	let (target, prefix, path) = route;
+	let incoming = match target.send(request, path, origin) {
+		Ok(incoming) => incoming,
+		Err(_) => return write_status(writer, StatusCode::BAD_GATEWAY, keep_alive),
+	};
+	let status =
+		StatusCode::from_u16(incoming.status()).unwrap_or(StatusCode::BAD_GATEWAY);
+	let mut headers = target.returned_headers(&incoming, prefix);
+	if !has_body(status) || request.method() == Method::HEAD {
+		return write_head(writer, status, &headers, keep_alive);
+	}
+
+	let script =
+		target.script.as_ref().filter(|_| incoming.content_type() == "text/html");
+	let mut body = incoming.into_reader();
+	if let Some(script) = script {
+		let mut html = Vec::new();
+		if body.read_to_end(&mut html).is_err() {
+			return write_status(writer, StatusCode::BAD_GATEWAY, keep_alive);
+		}
+		let html = inject_script(&html, script);
+		headers.push((CONTENT_LENGTH.to_string(), html.len().to_string()));
+		write_head(writer, status, &headers, keep_alive)?;
+		writer.write_all(&html)?;
+		return writer.flush();
+	}
+
+	headers.push((TRANSFER_ENCODING.to_string(), "chunked".to_string()));
+	write_head(writer, status, &headers, keep_alive)?;
+	let mut buffer = vec![0; 16 * 1024];
+	loop {
+		// A server failing halfway drops the connection, so the webview sees the body is cut
+		let read = body.read(&mut buffer)?;
+		if read == 0 {
+			break;
+		}
+		writer.write_all(format!("{:x}\r\n", read).as_bytes())?;
+		writer.write_all(&buffer[..read])?;
+		writer.write_all(b"\r\n")?;
+		writer.flush()?;
+	}
+	writer.write_all(b"0\r\n\r\n")?;
+	writer.flush()
+}
+
+/// Connects a WebSocket of the page to the server of a proxy mount, passing the bytes of
+/// both ways along until either side closes it. The handshake is forwarded like other
+/// requests, but the answer of the server is passed along as is
+/// # Arguments
+/// * `reader` - The connection of the webview, as read so far
+/// * `client` - The connection of the webview, to write to
+/// * `target` - The server
+/// * `request` - The handshake request of the webview
+/// * `path` - The path of the request relative to the mount
+/// * `origin` - The origin of the relay
+/// # Returns
+/// * `io::Result<()>` - Nothing, or why the connection broke
+fn tunnel(
+	reader: BufReader<TcpStream>, mut client: TcpStream, target: &ProxyTarget,
+	request: &Request<Vec<u8>>, path: &str, origin: &str,
+) -> io::Result<()> {
+	let url = target.url(request, path);
+	let address = url.trim_start_matches("http://");
+	let (authority, resource) =
+		address.split_at(address.find('/').unwrap_or(address.len()));
+	let mut upstream = match TcpStream::connect(authority) {
+		Ok(upstream) => upstream,
+		Err(_) => return write_status(&mut client, StatusCode::BAD_GATEWAY, false),
+	};
+	upstream.set_nodelay(true)?;
+
+	let mut head = format!(
+		"{} {} HTTP/1.1\r\nHost: {}\r\nConnection: Upgrade\r\n",
+		request.method(),
+		resource,
+		authority
+	);
+	for (name, value) in target.forwarded_headers(request, origin) {
+		if !name.contains(['\r', '\n']) && !value.contains(['\r', '\n']) {
+			head.push_str(&format!("{}: {}\r\n", name, value));
+		}
+	}
+	head.push_str("\r\n");
+	upstream.write_all(head.as_bytes())?;
+
+	let mut server = upstream.try_clone()?;
+	let sending = thread::spawn(move || {
+		let mut reader = reader;
+		let _ = io::copy(&mut reader, &mut server);
+		let _ = server.shutdown(Shutdown::Write);
+	});
+	let _ = io::copy(&mut upstream, &mut client);
+	let _ = client.shutdown(Shutdown::Both);
+	let _ = sending.join();
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn context() -> Context {
+		let router = Arc::new(Router::new(Vec::new(), Vec::new()));
+		Context { router, port: 4000, token: "secret".to_string() }
+	}
+
+	fn request(uri: &str, headers: &[(&str, &str)]) -> Request<Vec<u8>> {
+		let mut builder = Request::builder().uri(uri);
+		for (name, value) in headers {
+			builder = builder.header(*name, *value);
+		}
+		builder.body(Vec::new()).unwrap()
+	}
+
+	#[test]
+	fn reads_the_requests_of_a_connection() {
+		let mut reader: &[u8] = b"POST /api?q=1 HTTP/1.1\r\nHost: 127.0.0.1\r\nContent-Length: 3\r\n\r\nabc\
+			\r\nPUT /upload HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n2\r\nde\r\n1;x=y\r\nf\r\n0\r\n\r\n";
+		let first = read_request(&mut reader).unwrap().unwrap();
+		assert_eq!(first.method(), Method::POST);
+		assert_eq!(first.uri().query(), Some("q=1"));
+		assert_eq!(first.body(), b"abc");
+		let second = read_request(&mut reader).unwrap().unwrap();
+		assert_eq!(second.uri().path(), "/upload");
+		assert_eq!(second.body(), b"def");
+		assert!(read_request(&mut reader).unwrap().is_none());
+	}
+
+	#[test]
+	fn refuses_malformed_requests() {
+		let read = |bytes: &[u8]| read_request(&mut &bytes[..]).map(|_| ());
+		assert_eq!(read(b"GARBAGE\r\n\r\n"), Err(StatusCode::BAD_REQUEST));
+		assert_eq!(
+			read(b"GET http://example.com/ HTTP/1.1\r\n\r\n"),
+			Err(StatusCode::BAD_REQUEST)
+		);
+		assert_eq!(
+			read(b"GET / HTTP/2\r\n\r\n"),
+			Err(StatusCode::HTTP_VERSION_NOT_SUPPORTED)
+		);
+		assert_eq!(
+			read(b"GET / HTTP/1.1\r\nContent-Length: 9\r\n\r\nabc"),
+			Err(StatusCode::BAD_REQUEST)
+		);
+		let long = format!("GET / HTTP/1.1\r\nX: {}\r\n\r\n", "a".repeat(MAX_HEAD_SIZE));
+		assert_eq!(read(long.as_bytes()), Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE));
+	}
+
+	#[test]
+	fn only_the_window_gets_through() {
+		let context = context();
+		let host = ("Host", "127.0.0.1:4000");
+		let login = request("/page?a=1&pywry_relay=secret", &[host]);
+		assert_eq!(context.login(&login).as_deref(), Some("/page?a=1"));
+		assert_eq!(context.login(&request("/?pywry_relay=guess", &[host])), None);
+		let rebound = request("/?pywry_relay=secret", &[("Host", "evil.example:4000")]);
+		assert_eq!(context.login(&rebound), None);
+
+		let cookie = ("Cookie", "theme=dark; pywry_relay_4000=secret");
+		assert!(context.is_authorized(&request("/", &[host, cookie])));
+		assert!(!context.is_authorized(&request("/", &[host])));
+		assert!(
+			!context.is_authorized(&request("/", &[("Host", "localhost:4000"), cookie]))
+		);
+
+		let mut forwarded = request("/", &[host, cookie]);
+		context.strip_cookie(&mut forwarded);
+		assert_eq!(forwarded.headers()[COOKIE], "theme=dark");
+	}
+}
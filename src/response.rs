@@ -0,0 +1,368 @@
+use brotli::CompressorWriter;
+use flate2::{Compression, write::GzEncoder};
+use sha2::{Digest, Sha256};
+use std::{
+	borrow::Cow,
+	collections::HashMap,
+	fs::{File, metadata},
+	io::{self, Read, Seek, SeekFrom, Write},
+	path::{Path, PathBuf},
+	sync::{Mutex, PoisonError},
+	time::UNIX_EPOCH,
+};
+use wry::http::{
+	Request, Response, StatusCode,
+	header::{
+		ACCEPT_ENCODING, ACCEPT_RANGES, ACCESS_CONTROL_ALLOW_ORIGIN, CONTENT_ENCODING,
+		CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, ETAG, IF_NONE_MATCH, RANGE, VARY,
+	},
+};
+
+/// The origin of pages loaded from `wry://`, allowed to read every response.
+#[cfg(target_os = "windows")]
+pub const ORIGIN: &str = "https://wry.localhost";
+#[cfg(not(target_os = "windows"))]
+pub const ORIGIN: &str = "wry://localhost";
+
+/// Text responses smaller than this aren't worth compressing.
+const COMPRESS_MIN_SIZE: u64 = 1024;
+
+/// The response a custom protocol handler returns to the webview.
+pub type ProtocolResponse = wry::Result<Response<Cow<'static, [u8]>>>;
+
+/// Content held in memory, like the html of the window or its `assets`. Its tag is
+/// computed once and its compressed bodies are kept, as it never changes. Windows proxying
+/// a server serve it from the threads of their relay, so the cache is behind a lock.
+pub struct MemoryAsset {
+	bytes: Cow<'static, [u8]>,
+	tag: String,
+	/// The content compressed with each encoding it was requested with so far.
+	compressed: Mutex<HashMap<&'static str, Vec<u8>>>,
+}
+
+impl MemoryAsset {
+	pub fn new(bytes: impl Into<Cow<'static, [u8]>>) -> Self {
+		let bytes = bytes.into();
+		let digest = format!("{:x}", Sha256::digest(&bytes));
+		Self {
+			bytes,
+			tag: digest[..16].to_string(),
+			compressed: Mutex::new(HashMap::new()),
+		}
+	}
+
+	pub fn bytes(&self) -> &[u8] {
+		&self.bytes
+	}
+
+	/// Returns the content compressed with `encoding`, compressing it on the first request
+	fn compressed(&self, encoding: &'static str) -> io::Result<Cow<'static, [u8]>> {
+		let cache = || self.compressed.lock().unwrap_or_else(PoisonError::into_inner);
+		if let Some(body) = cache().get(encoding) {
+			return Ok(body.clone().into());
+		}
+		let body = compress(&self.bytes, encoding)?;
+		cache().insert(encoding, body.clone());
+		Ok(body.into())
+	}
+}
+
+/// The content a `wry://` request resolved to.
+pub enum Asset<'a> {
+	Memory(&'a MemoryAsset),
+	/// A file on disk, of which only the requested range is read.
+	File(PathBuf),
+}
+
+impl Asset<'_> {
+	/// Returns the size of the content and a tag that changes along with it
+	fn describe(&self) -> io::Result<(u64, String)> {
+		match self {
+			Self::Memory(asset) => Ok((asset.bytes.len() as u64, asset.tag.clone())),
+			Self::File(path) => {
+				let metadata = metadata(path)?;
+				let modified =
+					metadata.modified()?.duration_since(UNIX_EPOCH).unwrap_or_default();
+				Ok((metadata.len(), format!("{:x}-{:x}", metadata.len(), modified.as_nanos())))
+			}
+		}
+	}
+
+	/// Reads `length` bytes of the content, starting at `start`
+	fn read(&self, start: u64, length: u64) -> io::Result<Cow<'static, [u8]>> {
+		match self {
+			Self::Memory(asset) if start == 0 && length == asset.bytes.len() as u64 => {
+				Ok(asset.bytes.clone())
+			}
+			Self::Memory(asset) => {
+				Ok(asset.bytes[start as usize..(start + length) as usize].to_vec().into())
+			}
+			Self::File(path) => {
+				let mut file = File::open(path)?;
+				file.seek(SeekFrom::Start(start))?;
+				let mut data = Vec::new();
+				file.take(length).read_to_end(&mut data)?;
+				Ok(data.into())
+			}
+		}
+	}
+
+	/// Returns the whole content compressed with `encoding`, cached for memory assets
+	fn compressed(
+		&self, size: u64, encoding: &'static str,
+	) -> io::Result<Cow<'static, [u8]>> {
+		match self {
+			Self::Memory(asset) => asset.compressed(encoding),
+			Self::File(_) => compress(&self.read(0, size)?, encoding).map(Into::into),
+		}
+	}
+}
+
+/// Returns the MIME type a file is served with, guessed from its extension
+pub fn mime_type(path: &Path) -> String {
+	mime_guess::from_path(path).first_or_text_plain().to_string()
+}
+
+/// Parses the `Range` header of a request for `size` bytes. Only single ranges are
+/// honored, the whole content is sent for anything else, which HTTP allows
+/// # Arguments
+/// * `header` - The `Range` header of the request, if any
+/// * `size` - The size of the content
+/// # Returns
+/// * `Result<Option<(u64, u64)>, StatusCode>` - The first and last byte to send, `None` to
+/// send everything, or `RANGE_NOT_SATISFIABLE` when the range starts past the end
+fn parse_range(
+	header: Option<&str>, size: u64,
+) -> Result<Option<(u64, u64)>, StatusCode> {
+	let spec = match header.and_then(|header| header.trim().strip_prefix("bytes=")) {
+		Some(spec) if !spec.contains(',') => spec,
+		_ => return Ok(None),
+	};
+	let (start, end) = match spec.split_once('-') {
+		Some(range) => range,
+		None => return Ok(None),
+	};
+	let last = size.saturating_sub(1);
+	let (start, end) = match (start.trim().parse::<u64>(), end.trim().parse::<u64>()) {
+		// `bytes=-500` asks for the last 500 bytes
+		(Err(_), Ok(suffix)) if start.trim().is_empty() && suffix > 0 => {
+			(size.saturating_sub(suffix), last)
+		}
+		(Ok(start), Err(_)) if end.trim().is_empty() => (start, last),
+		(Ok(start), Ok(end)) if start <= end => (start, end.min(last)),
+		_ => return Ok(None),
+	};
+	match start < size {
+		true => Ok(Some((start, end))),
+		false => Err(StatusCode::RANGE_NOT_SATISFIABLE),
+	}
+}
+
+/// Picks the encoding to compress a response with, from the `Accept-Encoding` header of
+/// the request. Only text content big enough to benefit from it is compressed
+fn negotiate_encoding(
+	header: Option<&str>, mime: &str, size: u64,
+) -> Option<&'static str> {
+	let is_text = mime.starts_with("text/")
+		|| ["javascript", "json", "xml", "svg"].iter().any(|kind| mime.contains(kind));
+	if !is_text || size < COMPRESS_MIN_SIZE {
+		return None;
+	}
+	let accepted: Vec<&str> = header?
+		.split(',')
+		.filter_map(|token| {
+			let mut parts = token.split(';');
+			let name = parts.next()?.trim();
+			let refused = parts.any(|param| {
+				let quality = param.trim().strip_prefix("q=");
+				quality.and_then(|quality| quality.parse::<f32>().ok()) == Some(0.0)
+			});
+			(!refused).then_some(name)
+		})
+		.collect();
+	["br", "gzip"].into_iter().find(|encoding| accepted.contains(encoding))
+}
+
+fn compress(data: &[u8], encoding: &str) -> io::Result<Vec<u8>> {
+	match encoding {
+		"br" => {
+			let mut writer = CompressorWriter::new(Vec::new(), 4096, 5, 22);
+			writer.write_all(data)?;
+			Ok(writer.into_inner())
+		}
+		_ => {
+			let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+			encoder.write_all(data)?;
+			encoder.finish()
+		}
+	}
+}
+
+/// Answers a `wry://` request with an empty response, e.g. a `404` or `403`
+pub fn status_response(status: StatusCode) -> ProtocolResponse {
+	Response::builder()
+		.status(status)
+		.header(ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN)
+		.body(Vec::new().into())
+		.map_err(Into::into)
+}
+
+/// Answers a `wry://` request with `asset`, honoring the `If-None-Match`, `Range`
+/// and `Accept-Encoding` headers of the request
+/// # Arguments
+/// * `request` - The request of the webview
+/// * `asset` - The content the request resolved to
+/// * `mime` - The MIME type of the content
+/// # Returns
+/// * `ProtocolResponse` - The response, a `404` when the asset can't be read
+pub fn serve(request: &Request<Vec<u8>>, asset: Asset, mime: &str) -> ProtocolResponse {
+	let header = |name| request.headers().get(name).and_then(|value| value.to_str().ok());
+	let (size, tag) = match asset.describe() {
+		Ok(description) => description,
+		Err(_) => return status_response(StatusCode::NOT_FOUND),
+	};
+	let range = match parse_range(header(RANGE), size) {
+		Ok(range) => range,
+		Err(status) => {
+			return Response::builder()
+				.status(status)
+				.header(ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN)
+				.header(CONTENT_RANGE, format!("bytes */{}", size))
+				.body(Vec::new().into())
+				.map_err(Into::into);
+		}
+	};
+	// Ranges are offsets into the uncompressed content, so partial responses aren't compressed
+	let encoding = match range {
+		Some(_) => None,
+		None => negotiate_encoding(header(ACCEPT_ENCODING), mime, size),
+	};
+	let etag = match encoding {
+		Some(encoding) => format!("\"{}-{}\"", tag, encoding),
+		None => format!("\"{}\"", tag),
+	};
+
+	let response = Response::builder()
+		.header(ACCESS_CONTROL_ALLOW_ORIGIN, ORIGIN)
+		.header(CONTENT_TYPE, mime)
+		.header(ETAG, &etag)
+		.header(ACCEPT_RANGES, "bytes")
+		.header(VARY, ACCEPT_ENCODING.as_str());
+
+	let cached = header(IF_NONE_MATCH).is_some_and(|tags| {
+		tags
+			.split(',')
+			.map(|tag| tag.trim().trim_start_matches("W/"))
+			.any(|tag| tag == "*" || tag == etag)
+	});
+	if cached {
+		return response
+			.status(StatusCode::NOT_MODIFIED)
+			.body(Vec::new().into())
+			.map_err(Into::into);
+	}
+
+	// Content that fails to compress is sent as is
+	let compressed = encoding
+		.and_then(|encoding| Some((encoding, asset.compressed(size, encoding).ok()?)));
+	if let Some((encoding, body)) = compressed {
+		return response
+			.header(CONTENT_ENCODING, encoding)
+			.header(CONTENT_LENGTH, body.len())
+			.body(body)
+			.map_err(Into::into);
+	}
+
+	let (start, end) = range.unwrap_or((0, size.saturating_sub(1)));
+	let length = if size == 0 { 0 } else { end - start + 1 };
+	let body = match asset.read(start, length) {
+		Ok(body) => body,
+		Err(_) => return status_response(StatusCode::NOT_FOUND),
+	};
+	let response = match range {
+		Some(_) => response
+			.status(StatusCode::PARTIAL_CONTENT)
+			.header(CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size)),
+		None => response,
+	};
+	response.header(CONTENT_LENGTH, body.len()).body(body).map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_range_reads_single_ranges() {
+		assert_eq!(parse_range(Some("bytes=0-99"), 1000), Ok(Some((0, 99))));
+		assert_eq!(parse_range(Some("bytes=900-2000"), 1000), Ok(Some((900, 999))));
+		assert_eq!(parse_range(None, 1000), Ok(None));
+		assert_eq!(parse_range(Some("bytes=0-9,20-29"), 1000), Ok(None));
+		assert_eq!(parse_range(Some("bytes=20-10"), 1000), Ok(None));
+		assert_eq!(parse_range(Some("items=0-9"), 1000), Ok(None));
+	}
+
+	#[test]
+	fn parse_range_reads_suffix_and_open_ended_ranges() {
+		assert_eq!(parse_range(Some("bytes=-100"), 1000), Ok(Some((900, 999))));
+		assert_eq!(parse_range(Some("bytes=-5000"), 1000), Ok(Some((0, 999))));
+		assert_eq!(parse_range(Some("bytes=500-"), 1000), Ok(Some((500, 999))));
+		assert_eq!(parse_range(Some("bytes=-0"), 1000), Ok(None));
+	}
+
+	#[test]
+	fn parse_range_refuses_ranges_past_the_end() {
+		assert_eq!(
+			parse_range(Some("bytes=1000-"), 1000),
+			Err(StatusCode::RANGE_NOT_SATISFIABLE)
+		);
+		assert_eq!(
+			parse_range(Some("bytes=1500-2000"), 1000),
+			Err(StatusCode::RANGE_NOT_SATISFIABLE)
+		);
+		assert_eq!(
+			parse_range(Some("bytes=0-"), 0),
+			Err(StatusCode::RANGE_NOT_SATISFIABLE)
+		);
+	}
+
+	#[test]
+	fn negotiate_encoding_prefers_brotli() {
+		assert_eq!(
+			negotiate_encoding(Some("gzip, deflate, br"), "text/html", 4096),
+			Some("br")
+		);
+		assert_eq!(negotiate_encoding(Some("gzip"), "text/javascript", 4096), Some("gzip"));
+		assert_eq!(negotiate_encoding(Some("deflate"), "text/html", 4096), None);
+		assert_eq!(negotiate_encoding(None, "text/html", 4096), None);
+	}
+
+	#[test]
+	fn negotiate_encoding_skips_refused_encodings() {
+		assert_eq!(
+			negotiate_encoding(Some("br;q=0, gzip"), "text/html", 4096),
+			Some("gzip")
+		);
+		assert_eq!(
+			negotiate_encoding(Some("br; q=0.0, gzip;q=0"), "text/html", 4096),
+			None
+		);
+		assert_eq!(negotiate_encoding(Some("br;q=0.5"), "text/html", 4096), Some("br"));
+	}
+
+	#[test]
+	fn negotiate_encoding_skips_binary_and_small_content() {
+		assert_eq!(negotiate_encoding(Some("br"), "image/png", 4096), None);
+		assert_eq!(negotiate_encoding(Some("br"), "image/svg+xml", 4096), Some("br"));
+		assert_eq!(negotiate_encoding(Some("br"), "text/html", 100), None);
+	}
+
+	#[test]
+	fn memory_assets_keep_their_compressed_bodies() {
+		let asset = MemoryAsset::new("pywry ".repeat(1000).into_bytes());
+		let compressed = asset.compressed("gzip").unwrap();
+		assert!(compressed.len() < asset.bytes().len());
+		assert_eq!(asset.compressed("gzip").unwrap(), compressed);
+		assert_eq!(asset.compressed.lock().unwrap().len(), 1);
+	}
+}
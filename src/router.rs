@@ -0,0 +1,647 @@
+use crate::{
+	archive::read_archive,
+	protocol::{AssetContent, MountRequest},
+	response::{Asset, MemoryAsset, ProtocolResponse, mime_type, serve, status_response},
+	utils::resolve_asset,
+};
+use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
+use std::{
+	cmp::Reverse,
+	collections::{BTreeMap, HashMap},
+	fs::canonicalize,
+	path::{Path, PathBuf},
+	time::Duration,
+};
+use urlencoding::decode as urldecode;
+use wry::http::{
+	Request, StatusCode,
+	header::{
+		ACCEPT_ENCODING, CONNECTION, CONTENT_LENGTH, HOST, LOCATION, ORIGIN, REFERER,
+		TRANSFER_ENCODING,
+	},
+};
+
+/// How long a local HTTP server may take to accept the connection of a proxied request.
+/// Its answers take as long as they need, they are relayed off the event loop thread.
+const PROXY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Schemes the webview handles itself, which can't be served by a mount. WebKitGTK
+/// refuses to register them and WKWebView aborts the process, so `data://` mounts are
+/// replaced by mounts on a scheme of their own, e.g. `mem://`.
+const RESERVED_SCHEMES: [&str; 8] =
+	["http", "https", "file", "data", "blob", "about", "javascript", "ws"];
+
+/// Files served from memory, by path without its leading `/`.
+pub type MemoryFiles = HashMap<String, MemoryAsset>;
+
+/// Where the requests of a mount are served from.
+pub enum MountSource {
+	/// Files of the canonical directories, anything outside them gets a 403.
+	Directory(Vec<PathBuf>),
+	Memory(MemoryFiles),
+	/// A local HTTP server the requests are forwarded to.
+	Proxy(ProxyTarget),
+}
+
+/// A path of a custom protocol of a window, e.g. `wry://localhost/static`.
+pub struct Mount {
+	pub scheme: String,
+	/// The path the mount is served under, without a trailing `/`.
+	pub prefix: String,
+	pub source: MountSource,
+}
+
+impl Mount {
+	pub fn new(scheme: &str, prefix: &str, source: MountSource) -> Self {
+		let prefix = format!("/{}", prefix.trim_matches('/'));
+		Self { scheme: scheme.to_string(), prefix, source }
+	}
+
+	/// Parses a mount of a show message
+	/// # Arguments
+	/// * `request` - The mount as sent by the client
+	/// # Returns
+	/// * `Result<Mount, String>` - The mount or why it is invalid
+	pub fn from_request(request: MountRequest) -> Result<Self, String> {
+		let scheme = request.scheme.unwrap_or_else(|| "wry".to_string());
+		let valid_scheme = scheme.starts_with(|c: char| c.is_ascii_lowercase())
+			&& scheme
+				.chars()
+				.all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || "+-.".contains(c));
+		if RESERVED_SCHEMES.contains(&scheme.as_str()) {
+			return Err(format!(
+				"Invalid mount scheme {:?}, the webview handles it itself. Serve the mount on \
+				 a scheme of its own instead, e.g. \"mem\"",
+				scheme
+			));
+		}
+		if !valid_scheme {
+			return Err(format!("Invalid mount scheme {:?}", scheme));
+		}
+		let prefix = request.path.unwrap_or_default();
+
+		let source =
+			match (request.directory, request.assets, request.archive, request.proxy) {
+				(Some(directory), None, None, None) => {
+					let root = canonicalize(&directory).map_err(|error| {
+						format!("Invalid mount directory {:?}: {}", directory, error)
+					})?;
+					MountSource::Directory(vec![root])
+				}
+				(None, Some(assets), None, None) => MountSource::Memory(decode_assets(assets)?),
+				(None, None, Some(archive), None) => MountSource::Memory(
+					read_archive(&archive)?
+						.into_iter()
+						.map(|(path, data)| (path, MemoryAsset::new(data)))
+						.collect(),
+				),
+				// Proxied windows are loaded from a relay over HTTP, which only serves `wry`
+				(None, None, None, Some(_)) if scheme != "wry" => {
+					return Err(format!(
+						"Invalid mount scheme {:?}, proxies are only served on wry",
+						scheme
+					));
+				}
+				(None, None, None, Some(proxy)) => {
+					MountSource::Proxy(ProxyTarget::new(&proxy)?)
+				}
+				_ => {
+					return Err(
+						"Invalid mount, expected one of directory, assets, archive or proxy"
+							.to_string(),
+					);
+				}
+			};
+		Ok(Self::new(&scheme, &prefix, source))
+	}
+
+	/// Returns the path of a request relative to the mount, if the mount serves it
+	fn relative_path<'a>(&self, path: &'a str) -> Option<&'a str> {
+		let rest = path.strip_prefix(self.prefix.trim_end_matches('/'))?;
+		match rest.is_empty() || rest.starts_with('/') {
+			true => Some(rest.trim_start_matches('/')),
+			false => None,
+		}
+	}
+}
+
+/// Returns the schemes a window serves its mounts on, `wry` first
+pub fn mount_schemes(mounts: &[Mount]) -> Vec<String> {
+	let mut schemes = vec!["wry".to_string()];
+	for mount in mounts {
+		if !schemes.contains(&mount.scheme) {
+			schemes.push(mount.scheme.clone());
+		}
+	}
+	schemes
+}
+
+/// Decodes the `assets` of a show message, by path without its leading `/`
+pub fn decode_assets(
+	assets: BTreeMap<String, AssetContent>,
+) -> Result<MemoryFiles, String> {
+	assets
+		.into_iter()
+		.map(|(path, content)| {
+			let bytes = match content {
+				AssetContent::Text(text) => text.into_bytes(),
+				AssetContent::Base64 { base64 } => BASE64
+					.decode(base64)
+					.map_err(|error| format!("Invalid base64 for asset {}: {}", path, error))?,
+			};
+			Ok((path.trim_start_matches('/').to_string(), MemoryAsset::new(bytes)))
+		})
+		.collect()
+}
+
+/// Whether a url points at a HTTP server on this machine
+fn is_local_url(url: &str) -> bool {
+	let authority = match url.strip_prefix("http://") {
+		Some(rest) => rest.split('/').next().unwrap_or_default(),
+		None => return false,
+	};
+	let host = match authority.strip_prefix('[') {
+		Some(ipv6) => ipv6.split(']').next().unwrap_or_default(),
+		None => authority.split(':').next().unwrap_or_default(),
+	};
+	matches!(host, "127.0.0.1" | "localhost" | "::1")
+}
+
+/// Adds a script to an html page, at the start of its `<head>` when it has one
+pub fn inject_script(html: &[u8], script: &str) -> Vec<u8> {
+	let lowercase = html.to_ascii_lowercase();
+	let position = lowercase
+		.windows(5)
+		.position(|window| window == b"<head")
+		.and_then(|head| {
+			lowercase[head..].iter().position(|&byte| byte == b'>').map(|end| head + end + 1)
+		})
+		.unwrap_or(0);
+	let tag = format!("<script>{}</script>", script);
+	[&html[..position], tag.as_bytes(), &html[position..]].concat()
+}
+
+/// A HTTP server on this machine requests are forwarded to.
+pub struct ProxyTarget {
+	/// Base URL of the server, without a trailing `/`.
+	pub base: String,
+	/// Headers set on the requests forwarded to the server, or removed when empty.
+	pub request_headers: BTreeMap<String, String>,
+	/// Headers set on the responses of the server, or removed when empty.
+	pub response_headers: BTreeMap<String, String>,
+	/// Script added to the html pages of the server, if any.
+	pub script: Option<String>,
+	/// Doesn't follow redirects, so the webview sees them and updates the url of the page.
+	agent: ureq::Agent,
+}
+
+impl ProxyTarget {
+	/// Creates a proxy to a local HTTP server
+	/// # Arguments
+	/// * `url` - Base URL of the server, e.g. `http://127.0.0.1:8000`
+	/// # Returns
+	/// * `Result<ProxyTarget, String>` - The proxy, or an error when the server isn't local
+	pub fn new(url: &str) -> Result<Self, String> {
+		if !is_local_url(url) {
+			return Err(format!("Invalid proxy {}, expected a http://127.0.0.1 url", url));
+		}
+		Ok(Self {
+			base: url.trim_end_matches('/').to_string(),
+			request_headers: BTreeMap::new(),
+			response_headers: BTreeMap::new(),
+			script: None,
+			agent: ureq::AgentBuilder::new()
+				.redirects(0)
+				.timeout_connect(PROXY_CONNECT_TIMEOUT)
+				.build(),
+		})
+	}
+
+	pub fn with_request_headers(mut self, headers: BTreeMap<String, String>) -> Self {
+		self.request_headers = headers;
+		self
+	}
+
+	pub fn with_response_headers(mut self, headers: BTreeMap<String, String>) -> Self {
+		self.response_headers = headers;
+		self
+	}
+
+	pub fn with_script(mut self, script: String) -> Self {
+		self.script = Some(script);
+		self
+	}
+
+	/// Rewrites a header sent by the webview so it makes sense to the server, `None` drops it
+	fn request_header(&self, name: &str, value: &str, origin: &str) -> Option<String> {
+		if [HOST, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING]
+			.iter()
+			.any(|header| header.as_str().eq_ignore_ascii_case(name))
+		{
+			return None;
+		}
+		// Pages are scanned for `<head>` to inject the script, so they must not be compressed
+		if self.script.is_some() && ACCEPT_ENCODING.as_str().eq_ignore_ascii_case(name) {
+			return None;
+		}
+		// Servers checking where requests come from expect their own origin, not the relay's
+		let is_origin =
+			[ORIGIN, REFERER].iter().any(|header| header.as_str().eq_ignore_ascii_case(name));
+		match value.strip_prefix(origin) {
+			Some(rest) if is_origin => Some(format!("{}{}", self.base, rest)),
+			_ => Some(value.to_string()),
+		}
+	}
+
+	/// Rewrites a header sent by the server so it makes sense to the webview, `None` drops it
+	fn response_header(&self, name: &str, value: &str, prefix: &str) -> Option<String> {
+		if [CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING]
+			.iter()
+			.any(|header| header.as_str().eq_ignore_ascii_case(name))
+		{
+			return None;
+		}
+		// Redirects to the server stay on the relay, under the path of the mount
+		match value.strip_prefix(&self.base) {
+			Some(rest) if LOCATION.as_str().eq_ignore_ascii_case(name) => Some(format!(
+				"{}/{}",
+				prefix.trim_end_matches('/'),
+				rest.trim_start_matches('/')
+			)),
+			_ => Some(value.to_string()),
+		}
+	}
+
+	/// Returns the url of the server a request of the webview goes to
+	/// # Arguments
+	/// * `request` - The request of the webview
+	/// * `path` - The path of the request relative to the mount
+	/// # Returns
+	/// * `String` - The url, query included
+	pub fn url(&self, request: &Request<Vec<u8>>, path: &str) -> String {
+		let query =
+			request.uri().query().map(|query| format!("?{}", query)).unwrap_or_default();
+		format!("{}/{}{}", self.base, path, query)
+	}
+
+	/// Returns the headers of a request of the webview rewritten for the server, along
+	/// with the `request_headers` set on it
+	/// # Arguments
+	/// * `request` - The request of the webview
+	/// * `origin` - The origin the webview loads the pages of the window from
+	/// # Returns
+	/// * `Vec<(String, String)>` - The headers to send the server
+	pub fn forwarded_headers(
+		&self, request: &Request<Vec<u8>>, origin: &str,
+	) -> Vec<(String, String)> {
+		let overridden = |name: &str| {
+			self.request_headers.keys().any(|header| header.eq_ignore_ascii_case(name))
+		};
+		let mut headers: Vec<(String, String)> = request
+			.headers()
+			.iter()
+			.filter(|(name, _)| !overridden(name.as_str()))
+			.filter_map(|(name, value)| {
+				let value = self.request_header(name.as_str(), value.to_str().ok()?, origin)?;
+				Some((name.to_string(), value))
+			})
+			.collect();
+		headers.extend(
+			self
+				.request_headers
+				.iter()
+				.filter(|(_, value)| !value.is_empty())
+				.map(|(name, value)| (name.clone(), value.clone())),
+		);
+		headers
+	}
+
+	/// Returns the headers of a response of the server rewritten for the webview, along
+	/// with the `response_headers` set on it
+	/// # Arguments
+	/// * `response` - The response of the server
+	/// * `prefix` - The path the mount is served under
+	/// # Returns
+	/// * `Vec<(String, String)>` - The headers to send the webview
+	pub fn returned_headers(
+		&self, response: &ureq::Response, prefix: &str,
+	) -> Vec<(String, String)> {
+		let mut headers = Vec::new();
+		for name in response.headers_names() {
+			if self.response_headers.keys().any(|header| header.eq_ignore_ascii_case(&name)) {
+				continue;
+			}
+			for value in response.all(&name) {
+				if let Some(value) = self.response_header(&name, value, prefix) {
+					headers.push((name.clone(), value));
+				}
+			}
+		}
+		headers.extend(
+			self
+				.response_headers
+				.iter()
+				.filter(|(_, value)| !value.is_empty())
+				.map(|(name, value)| (name.clone(), value.clone())),
+		);
+		headers
+	}
+
+	/// Forwards a request of the webview to the server. It waits for the server as long
+	/// as it takes, so it's only called from the threads of the relay of the window
+	/// # Arguments
+	/// * `request` - The request of the webview
+	/// * `path` - The path of the request relative to the mount
+	/// * `origin` - The origin the webview loads the pages of the window from
+	/// # Returns
+	/// * `Result<ureq::Response, String>` - The response of the server, whatever its status,
+	///   or why it can't be reached
+	pub fn send(
+		&self, request: &Request<Vec<u8>>, path: &str, origin: &str,
+	) -> Result<ureq::Response, String> {
+		let mut outgoing =
+			self.agent.request(request.method().as_str(), &self.url(request, path));
+		for (name, value) in self.forwarded_headers(request, origin) {
+			outgoing = outgoing.set(&name, &value);
+		}
+		match outgoing.send_bytes(request.body()) {
+			Ok(response) | Err(ureq::Error::Status(_, response)) => Ok(response),
+			Err(error) => Err(error.to_string()),
+		}
+	}
+}
+
+/// Where a request of a window is answered.
+pub enum Route<'a> {
+	/// A response of the window itself, from memory or a directory.
+	Response(ProtocolResponse),
+	/// A request for a local server, forwarded to it by the relay of the window.
+	Proxy {
+		target: &'a ProxyTarget,
+		/// The path the mount is served under.
+		prefix: &'a str,
+		/// The path of the request relative to the mount.
+		path: &'a str,
+	},
+}
+
+/// Routes the requests of the custom protocols of a window to its mounts.
+///
+/// Mounts with longer paths are tried first, and a request a mount doesn't have falls
+/// through to the next one, so memory assets can sit over a directory at the same path.
+/// Windows proxying a server share it with the threads of their relay, see `relay`.
+pub struct Router {
+	mounts: Vec<Mount>,
+	/// The html of the window, served at `wry://localhost/` unless a server is.
+	index: Option<MemoryAsset>,
+	/// Whether paths without an extension that no mount serves get the index,
+	/// for client-side routing.
+	index_fallback: bool,
+	/// Called with the path of requests refused for pointing outside a directory.
+	on_denied: Option<Box<dyn Fn(&str) + Send + Sync>>,
+}
+
+impl Router {
+	pub fn new(mut mounts: Vec<Mount>, index: Vec<u8>) -> Self {
+		mounts.sort_by_key(|mount| Reverse(mount.prefix.len()));
+		let proxies_root = mounts.iter().any(|mount| {
+			mount.scheme == "wry"
+				&& mount.prefix == "/"
+				&& matches!(mount.source, MountSource::Proxy(_))
+		});
+		let index = (!proxies_root).then(|| MemoryAsset::new(index));
+		Self { mounts, index, index_fallback: false, on_denied: None }
+	}
+
+	pub fn with_index_fallback(mut self, index_fallback: bool) -> Self {
+		self.index_fallback = index_fallback;
+		self
+	}
+
+	pub fn with_denied_handler(
+		mut self, on_denied: impl Fn(&str) + Send + Sync + 'static,
+	) -> Self {
+		self.on_denied = Some(Box::new(on_denied));
+		self
+	}
+
+	/// Returns the schemes to register a custom protocol for, `wry` first
+	pub fn schemes(&self) -> Vec<String> {
+		mount_schemes(&self.mounts)
+	}
+
+	/// Whether the window proxies a local server, and so is loaded from a relay
+	pub fn proxies(&self) -> bool {
+		self.mounts.iter().any(|mount| matches!(mount.source, MountSource::Proxy(_)))
+	}
+
+	/// Answers a request made to one of the custom protocols of the window. Proxied
+	/// requests are answered by the relay of the window, they get a `502` here
+	/// # Arguments
+	/// * `scheme` - The scheme the request was made to
+	/// * `request` - The request of the webview
+	/// # Returns
+	/// * `ProtocolResponse` - The response of the first mount that serves the path, or a `404`
+	pub fn handle(&self, scheme: &str, request: &Request<Vec<u8>>) -> ProtocolResponse {
+		match self.route(scheme, request) {
+			Route::Response(response) => response,
+			Route::Proxy { .. } => status_response(StatusCode::BAD_GATEWAY),
+		}
+	}
+
+	/// Finds where a request of the window is answered
+	/// # Arguments
+	/// * `scheme` - The scheme the request was made to
+	/// * `request` - The request of the webview
+	/// # Returns
+	/// * `Route` - The response of the first mount that serves the path, a `404` when none
+	///   does, or the server a proxy mount forwards it to
+	pub fn route<'a>(&'a self, scheme: &str, request: &'a Request<Vec<u8>>) -> Route<'a> {
+		let path = request.uri().path();
+		let index = self.index.as_ref().filter(|_| scheme == "wry");
+		if let Some(index) = index.filter(|_| path == "/") {
+			return Route::Response(serve(request, Asset::Memory(index), "text/html"));
+		}
+
+		for mount in self.mounts.iter().filter(|mount| mount.scheme == scheme) {
+			let relative = match mount.relative_path(path) {
+				Some(relative) => relative,
+				None => continue,
+			};
+			match &mount.source {
+				MountSource::Memory(files) => {
+					let file = urldecode(relative).ok().and_then(|path| files.get(&*path));
+					if let Some(file) = file {
+						let mime = mime_type(Path::new(relative));
+						return Route::Response(serve(request, Asset::Memory(file), &mime));
+					}
+				}
+				MountSource::Directory(roots) => match resolve_asset(relative, roots) {
+					Ok(file) => {
+						let mime = mime_type(&file);
+						return Route::Response(serve(request, Asset::File(file), &mime));
+					}
+					Err(StatusCode::FORBIDDEN) => {
+						if let Some(on_denied) = &self.on_denied {
+							on_denied(path);
+						}
+						return Route::Response(status_response(StatusCode::FORBIDDEN));
+					}
+					Err(_) => {}
+				},
+				MountSource::Proxy(target) => {
+					return Route::Proxy { target, prefix: &mount.prefix, path: relative };
+				}
+			}
+		}
+
+		// Client-side routes of single page apps, like `/users/3`, are handled by their index.html
+		if let Some(index) = index.filter(|_| self.index_fallback) {
+			if Path::new(path).extension().is_none() {
+				return Route::Response(serve(request, Asset::Memory(index), "text/html"));
+			}
+		}
+		Route::Response(status_response(StatusCode::NOT_FOUND))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::{
+		env,
+		fs::{create_dir_all, write},
+	};
+
+	fn memory(files: &[(&str, &str)]) -> MountSource {
+		MountSource::Memory(
+			files
+				.iter()
+				.map(|(path, text)| {
+					(path.to_string(), MemoryAsset::new(text.as_bytes().to_vec()))
+				})
+				.collect(),
+		)
+	}
+
+	/// Returns the status and body of a request to the router
+	fn get(router: &Router, url: &str) -> (StatusCode, Vec<u8>) {
+		let scheme = url.split(':').next().unwrap_or_default();
+		let request = Request::builder().uri(url).body(Vec::new()).unwrap();
+		let response = router.handle(scheme, &request).unwrap();
+		(response.status(), response.body().to_vec())
+	}
+
+	fn mount_request(scheme: &str, proxy: &str) -> MountRequest {
+		MountRequest {
+			scheme: Some(scheme.to_string()),
+			path: None,
+			directory: None,
+			assets: None,
+			archive: None,
+			proxy: Some(proxy.to_string()),
+		}
+	}
+
+	#[test]
+	fn relative_path_matches_whole_segments() {
+		let mount = Mount::new("wry", "static/", memory(&[]));
+		assert_eq!(mount.prefix, "/static");
+		assert_eq!(mount.relative_path("/static"), Some(""));
+		assert_eq!(mount.relative_path("/static/css/app.css"), Some("css/app.css"));
+		assert_eq!(mount.relative_path("/statics/app.css"), None);
+		assert_eq!(mount.relative_path("/app.css"), None);
+		let root = Mount::new("wry", "", memory(&[]));
+		assert_eq!(root.relative_path("/app.css"), Some("app.css"));
+	}
+
+	#[test]
+	fn longer_prefixes_are_tried_first() {
+		let router = Router::new(
+			vec![
+				Mount::new("wry", "/", memory(&[("static/a.txt", "root")])),
+				Mount::new("wry", "/static", memory(&[("a.txt", "static")])),
+			],
+			b"index".to_vec(),
+		);
+		assert_eq!(get(&router, "wry://localhost/static/a.txt").1, b"static");
+		assert_eq!(get(&router, "wry://localhost/").1, b"index");
+	}
+
+	#[test]
+	fn paths_a_mount_lacks_fall_through() {
+		let root = env::temp_dir().join(format!("pywry-router-{}", std::process::id()));
+		create_dir_all(&root).unwrap();
+		write(root.join("b.txt"), "directory").unwrap();
+		write(root.join("a.txt"), "directory").unwrap();
+		let root = canonicalize(root).unwrap();
+		let router = Router::new(
+			vec![
+				Mount::new("wry", "/", memory(&[("a.txt", "memory")])),
+				Mount::new("wry", "/", MountSource::Directory(vec![root])),
+			],
+			Vec::new(),
+		);
+		assert_eq!(get(&router, "wry://localhost/a.txt").1, b"memory");
+		assert_eq!(get(&router, "wry://localhost/b.txt").1, b"directory");
+		assert_eq!(get(&router, "wry://localhost/c.txt").0, StatusCode::NOT_FOUND);
+	}
+
+	#[test]
+	fn mounts_only_serve_their_scheme() {
+		let router = Router::new(
+			vec![Mount::new("mem", "/", memory(&[("data.json", "[1]")]))],
+			b"index".to_vec(),
+		);
+		assert_eq!(router.schemes(), ["wry", "mem"]);
+		assert_eq!(get(&router, "mem://localhost/data.json").1, b"[1]");
+		assert_eq!(get(&router, "wry://localhost/data.json").0, StatusCode::NOT_FOUND);
+		// The index belongs to `wry://`
+		assert_eq!(get(&router, "mem://localhost/").0, StatusCode::NOT_FOUND);
+	}
+
+	#[test]
+	fn index_fallback_serves_client_side_routes() {
+		let mounts = || vec![Mount::new("wry", "/", memory(&[("app.js", "js")]))];
+		let router = Router::new(mounts(), b"index".to_vec()).with_index_fallback(true);
+		assert_eq!(get(&router, "wry://localhost/users/3").1, b"index");
+		assert_eq!(get(&router, "wry://localhost/app.js").1, b"js");
+		assert_eq!(get(&router, "wry://localhost/missing.js").0, StatusCode::NOT_FOUND);
+		let router = Router::new(mounts(), b"index".to_vec());
+		assert_eq!(get(&router, "wry://localhost/users/3").0, StatusCode::NOT_FOUND);
+	}
+
+	#[test]
+	fn a_proxied_root_replaces_the_index() {
+		let proxy = ProxyTarget::new("http://127.0.0.1:1").unwrap();
+		let router = Router::new(
+			vec![
+				Mount::new("wry", "/static", memory(&[("a.txt", "static")])),
+				Mount::new("wry", "/", MountSource::Proxy(proxy)),
+			],
+			b"index".to_vec(),
+		);
+		assert!(router.proxies());
+		let request = Request::builder().uri("wry://localhost/").body(Vec::new()).unwrap();
+		assert!(matches!(
+			router.route("wry", &request),
+			Route::Proxy { prefix: "/", path: "", .. }
+		));
+		assert_eq!(get(&router, "wry://localhost/static/a.txt").1, b"static");
+		// Custom protocols never wait on a server, the relay of the window does
+		assert_eq!(get(&router, "wry://localhost/").0, StatusCode::BAD_GATEWAY);
+	}
+
+	#[test]
+	fn proxies_are_local_and_on_wry() {
+		assert!(Mount::from_request(mount_request("wry", "http://127.0.0.1:8000")).is_ok());
+		assert!(
+			Mount::from_request(mount_request("wry", "http://localhost:8000/")).is_ok()
+		);
+		assert!(
+			Mount::from_request(mount_request("mem", "http://127.0.0.1:8000")).is_err()
+		);
+		assert!(Mount::from_request(mount_request("wry", "http://example.com")).is_err());
+		assert!(Mount::from_request(mount_request("wry", "https://127.0.0.1")).is_err());
+	}
+}
@@ -0,0 +1,196 @@
+use serde_json::{Map, Value, json};
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::protocol::{WindowCommand, WindowHandle};
+
+/// How often points buffered from `stream` commands are sent to their windows,
+/// about once per frame
+pub const STREAM_FRAME: Duration = Duration::from_millis(16);
+
+/// Points waiting to be appended to the traces of one window with `Plotly.extendTraces`
+pub struct StreamFrame {
+	pub window: WindowHandle,
+	/// The `id` of the latest command merged into the frame, echoed back on errors
+	pub request_id: Option<Value>,
+	/// The points of each trace, by trace index and attribute, e.g. `x` and `y`
+	traces: BTreeMap<usize, BTreeMap<String, Vec<Value>>>,
+	max_points: Option<usize>,
+}
+
+impl StreamFrame {
+	/// Creates a frame from a `stream` command, or returns None for any other command
+	/// # Arguments
+	/// * `command` - The command read from stdin
+	/// # Returns
+	/// * `Option<StreamFrame>` - The points of the command
+	pub fn new(command: WindowCommand) -> Option<Self> {
+		let WindowCommand::Stream { id, window, traces, data, max_points } = command else {
+			return None;
+		};
+		let mut points: BTreeMap<usize, BTreeMap<String, Vec<Value>>> = BTreeMap::new();
+		for (attribute, values) in data {
+			for (trace, values) in traces.iter().zip(values) {
+				points
+					.entry(*trace)
+					.or_default()
+					.entry(attribute.clone())
+					.or_default()
+					.extend(values);
+			}
+		}
+		let mut frame = Self { window, request_id: id, traces: points, max_points };
+		frame.trim();
+		Some(frame)
+	}
+
+	/// Drops the points Plotly would discard right away to keep `max_points` per trace
+	fn trim(&mut self) {
+		let Some(max_points) = self.max_points else {
+			return;
+		};
+		for values in self.traces.values_mut().flat_map(BTreeMap::values_mut) {
+			let excess = values.len().saturating_sub(max_points);
+			values.drain(..excess);
+		}
+	}
+
+	/// Appends the points of a later frame for the same window, the latest
+	/// `id` and `max_points` win
+	fn append(&mut self, frame: Self) {
+		for (trace, attributes) in frame.traces {
+			for (attribute, values) in attributes {
+				self
+					.traces
+					.entry(trace)
+					.or_default()
+					.entry(attribute)
+					.or_default()
+					.extend(values);
+			}
+		}
+		if frame.request_id.is_some() {
+			self.request_id = frame.request_id;
+		}
+		if frame.max_points.is_some() {
+			self.max_points = frame.max_points;
+		}
+		self.trim();
+	}
+
+	/// The script appending every point of the frame in a single `Plotly.extendTraces` call
+	pub fn to_script(&self) -> String {
+		let mut update: BTreeMap<&str, Vec<&[Value]>> = BTreeMap::new();
+		for attributes in self.traces.values() {
+			for attribute in attributes.keys() {
+				update.entry(attribute).or_default();
+			}
+		}
+		// Plotly expects every attribute for every trace, traces missing one get no points
+		for (attribute, points) in &mut update {
+			for attributes in self.traces.values() {
+				points.push(attributes.get(*attribute).map_or(&[], Vec::as_slice));
+			}
+		}
+		let update: Map<String, Value> = update
+			.into_iter()
+			.map(|(key, points)| (key.to_string(), json!(points)))
+			.collect();
+		let indices: Vec<&usize> = self.traces.keys().collect();
+
+		format!(
+			"pywry_extend({}, {}, {});",
+			Value::Object(update),
+			json!(indices),
+			json!(self.max_points)
+		)
+	}
+}
+
+/// Buffers the points of `stream` commands read from stdin, so a fast producer
+/// sends one frame per window to the event loop instead of one event per line
+#[derive(Default)]
+pub struct StreamBuffer {
+	frames: Vec<StreamFrame>,
+}
+
+impl StreamBuffer {
+	pub fn is_empty(&self) -> bool {
+		self.frames.is_empty()
+	}
+
+	/// Adds the points of a `stream` command to the frame of its window
+	pub fn push(&mut self, frame: StreamFrame) {
+		match self.frames.iter_mut().find(|pending| pending.window == frame.window) {
+			Some(pending) => pending.append(frame),
+			None => self.frames.push(frame),
+		}
+	}
+
+	/// Empties the buffer, returning the frames in the order their windows were first streamed to
+	pub fn take(&mut self) -> Vec<StreamFrame> {
+		std::mem::take(&mut self.frames)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn frame(
+		window: &str, traces: Vec<usize>, data: Value, max_points: Option<usize>,
+	) -> StreamFrame {
+		let command = WindowCommand::Stream {
+			id: None,
+			window: WindowHandle::Label(window.to_string()),
+			traces,
+			data: serde_json::from_value(data).unwrap(),
+			max_points,
+		};
+		StreamFrame::new(command).unwrap()
+	}
+
+	#[test]
+	fn to_script_sends_every_attribute_for_every_trace() {
+		let frame =
+			frame("chart", vec![0, 2], json!({"x": [[1], [2]], "y": [[3], []]}), None);
+		assert_eq!(
+			frame.to_script(),
+			r#"pywry_extend({"x":[[1],[2]],"y":[[3],[]]}, [0,2], null);"#
+		);
+	}
+
+	#[test]
+	fn max_points_keeps_the_latest_points() {
+		let frame = frame("chart", vec![0], json!({"x": [[1, 2, 3, 4]]}), Some(2));
+		assert_eq!(frame.to_script(), r#"pywry_extend({"x":[[3,4]]}, [0], 2);"#);
+	}
+
+	#[test]
+	fn append_trims_to_the_latest_max_points() {
+		let mut first = frame("chart", vec![0], json!({"x": [[1, 2]]}), None);
+		first.append(frame("chart", vec![0], json!({"x": [[3, 4]]}), Some(3)));
+		assert_eq!(first.to_script(), r#"pywry_extend({"x":[[2,3,4]]}, [0], 3);"#);
+	}
+
+	#[test]
+	fn push_coalesces_the_frames_of_a_window() {
+		let mut buffer = StreamBuffer::default();
+		buffer.push(frame("a", vec![0], json!({"x": [[1]]}), None));
+		buffer.push(frame("b", vec![0], json!({"x": [[2]]}), None));
+		let mut later =
+			frame("a", vec![0, 1], json!({"x": [[3], [4]], "y": [[5], [6]]}), None);
+		later.request_id = Some(json!(7));
+		buffer.push(later);
+
+		let frames = buffer.take();
+		assert!(buffer.is_empty());
+		assert_eq!(frames.len(), 2);
+		assert_eq!(frames[0].window, WindowHandle::Label("a".to_string()));
+		assert_eq!(frames[0].request_id, Some(json!(7)));
+		assert_eq!(
+			frames[0].to_script(),
+			r#"pywry_extend({"x":[[1,3],[4]],"y":[[5],[6]]}, [0,1], null);"#
+		);
+		assert_eq!(frames[1].window, WindowHandle::Label("b".to_string()));
+	}
+}
@@ -1,13 +1,36 @@
+use crate::{
+	archive::read_archive,
+	constants::{PLOTLY_JS, PROXY_INJECT_JS},
+	navigation::NavigationPolicy,
+	protocol::{
+		ClientEvent, ConflictPolicy, ConsoleLevel, ErrorReply, ExportTarget, ImageFormat,
+		JsonData, LogMessage, NavigationAction, Outgoing, PlotlyEventName, ShowRequest,
+		WindowHandle,
+	},
+	relay::Relay,
+	response::MemoryAsset,
+	router::{
+		MemoryFiles, Mount, MountSource, ProxyTarget, decode_assets, mount_schemes,
+	},
+	stream::StreamFrame,
+	utils::get_plotly_version,
+};
+use serde::Serialize;
 use serde_json::Value;
-#[cfg(not(target_os = "windows"))]
-use wry::application::window::Icon;
-use wry::application::window::{Theme, WindowId};
+use wry::{
+	application::window::{Theme, WindowId},
+	webview::WebView,
+};
 
 use std::{
-	convert::TryFrom,
-	fs::{canonicalize, read_to_string},
+	borrow::Cow,
+	collections::{HashMap, VecDeque},
+	fs::{canonicalize, read, read_to_string},
 	io::{self, Write},
-	path::PathBuf,
+	path::{Path, PathBuf},
+	rc::Rc,
+	sync::atomic::{AtomicU64, Ordering},
+	time::{Duration, Instant},
 };
 
 /// A struct for printing logs as JSON messages to the console.
@@ -53,56 +76,259 @@ impl ConsolePrinter {
 		Self { active }
 	}
 
-	pub fn get_json(&self, message: &str, level: &str) -> String {
-		match serde_json::from_str(&format!("{{\"{}\": \"{}\"}}", level, message)) {
-			Ok(json) => json,
-			Err(_) => serde_json::json!({}),
-		}
-		.to_string()
-	}
-
 	pub fn debug(&self, message: &str) {
 		if self.active {
-			self.stdout_handler(message, "debug");
+			self.send(LogMessage::Debug(message.to_string()), None);
 		}
 	}
 
 	pub fn info(&self, message: &str) {
-		self.stdout_handler(message, "info");
+		self.send(LogMessage::Info(message.to_string()), None);
 	}
 
 	pub fn error(&self, message: &str) {
-		self.stdout_handler(message, "error");
+		self.send(LogMessage::Error(message.to_string()), None);
+	}
+
+	/// Prints a JSON message to stdout, tagged with the `id` of the request that caused it.
+	/// The `id` key is left out when the request didn't carry one.
+	pub fn send<T: Serialize>(&self, message: T, request_id: Option<&Value>) {
+		let outgoing = Outgoing { message, id: request_id };
+		match serde_json::to_string(&outgoing) {
+			Ok(json) => self.write_line(json),
+			Err(error) => self.error(&format!("Error serializing message: {}", error)),
+		}
+	}
+
+	/// Prints a structured error to stdout, tagged with the `id` of the request that caused it.
+	pub fn send_error(&self, code: &str, message: &str, request_id: Option<&Value>) {
+		self.send(ErrorReply::new(code, message), request_id);
 	}
 
-	pub fn stdout_handler(&self, message: &str, level: &str) {
-		let json = self.get_json(message, level);
-		std::thread::spawn(move || {
-			let stdout = io::stdout();
-			let mut handler = stdout.lock();
-			handler.write_all(format!("{}\n", json).as_bytes()).unwrap();
-			handler.flush().unwrap();
-		});
+	/// Writes a line to stdout right away, under its lock, so the messages of a request
+	/// reach the client in the order they were sent
+	fn write_line(&self, json: String) {
+		let mut handler = io::stdout().lock();
+		writeln!(handler, "{}", json).and_then(|()| handler.flush()).unwrap_or_default();
 	}
 }
 
 pub enum UserEvent {
+	DownloadStarted(u64, String, PathBuf, WindowId),
 	#[cfg(not(target_os = "macos"))]
-	DownloadStarted(String, String),
-	#[cfg(not(target_os = "macos"))]
-	DownloadComplete(Option<PathBuf>, bool, String, String, WindowId),
+	DownloadComplete(
+		String,
+		Option<PathBuf>,
+		bool,
+		String,
+		String,
+		ConflictPolicy,
+		WindowId,
+	),
+	#[cfg(target_os = "macos")]
+	DownloadSaved(String, Option<PathBuf>, bool, WindowId),
+	DownloadFailed(u64, String, WindowId),
+	/// A download saved by a worker thread, with its path, size and SHA-256, and whether
+	/// its window closes now.
+	DownloadFinished(u64, Result<(PathBuf, u64, String), String>, bool, WindowId),
 	#[cfg(not(target_os = "macos"))]
-	BlobReceived(String, WindowId),
-	BlobChunk(Option<String>),
+	BlobReceived(u64, String, PathBuf, String, String, ConflictPolicy, WindowId),
+	BlobStarted(u64, Option<u64>, WindowId),
+	BlobChunk(u64, Option<String>, WindowId),
+	CallReceived(String, WindowId),
+	EvalResult(String, WindowId),
+	PlotlyEvent(String, WindowId),
+	ConsoleMessage(String, WindowId),
+	AssetDenied(String, WindowId),
+	Navigation(String, bool, NavigationAction, WindowId),
+	OpenBrowser(String),
 	CloseWindow(WindowId),
+	CommandReceived(String, Option<Value>),
 	DevTools(WindowId),
 	NewWindowCreated(WindowId),
-	NewPlot(String, WindowId),
+	NewPlot(RenderJob, WindowId),
+	Notify(ClientEvent, WindowId),
 	OpenFile(Option<PathBuf>),
-	STDout(String),
-	#[cfg(not(target_os = "windows"))]
-	NewWindow(String, Option<Icon>),
-	NewMessageReceived(String),
+	RendererReady(WindowId),
+	RenderFailed(String, WindowId),
+	STDout(String, WindowId),
+	StreamFrames(Vec<StreamFrame>),
+	/// A url to open in a new window, along with the path of its icon and the window opening it.
+	NewWindow(String, String, WindowId),
+	NewMessageReceived(String, Option<Value>),
+}
+
+/// A webview owned by the event loop, along with the request it is serving.
+pub struct WindowState {
+	pub webview: WebView,
+	/// Identifies the window in the messages exchanged with the client.
+	pub handle: WindowHandle,
+	/// The `id` of the request currently served by the window, echoed back
+	/// on every message the window sends to stdout.
+	pub request_id: Option<Value>,
+	/// What a headless renderer is doing, unused by other windows.
+	pub render_status: RenderStatus,
+	/// Scripts run by `eval` commands that haven't replied yet, by eval id.
+	pub evals: HashMap<u64, PendingEval>,
+	/// The id of the latest eval run in the window.
+	pub last_eval: u64,
+	/// Downloads started by the page that weren't saved, failed or cancelled yet.
+	pub downloads: Vec<Download>,
+	/// Where the downloads of an export window go.
+	pub export_to: ExportTarget,
+	/// Where the window may navigate to, shared with its navigation handlers.
+	pub navigation: Option<Rc<NavigationPolicy>>,
+	/// Resize and move events held back while the window is dragged.
+	pub geometry_events: GeometryEvents,
+	/// The relay the pages of a window proxying a server are loaded from, stopped
+	/// along with the window.
+	pub relay: Option<Relay>,
+}
+
+impl WindowState {
+	pub fn new(
+		webview: WebView, request_id: Option<Value>, handle: WindowHandle,
+	) -> Self {
+		Self {
+			webview,
+			handle,
+			request_id,
+			render_status: RenderStatus::Loading,
+			evals: HashMap::new(),
+			last_eval: 0,
+			downloads: Vec::new(),
+			export_to: ExportTarget::File,
+			navigation: None,
+			geometry_events: GeometryEvents::default(),
+			relay: None,
+		}
+	}
+
+	/// Returns the earliest time a render job or eval of the window times out, or its
+	/// held back resize and move events are due, if any
+	pub fn deadline(&self) -> Option<Instant> {
+		let render_deadline = match self.render_status {
+			RenderStatus::Busy(deadline) => Some(deadline),
+			_ => None,
+		};
+		self
+			.evals
+			.values()
+			.map(|eval| eval.deadline)
+			.chain(render_deadline)
+			.chain(self.geometry_events.deadline())
+			.min()
+	}
+}
+
+/// How often a window being resized or moved tells the client about it.
+pub const GEOMETRY_EVENT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The resize and move events of a window not sent yet, at most one of each is sent
+/// every `GEOMETRY_EVENT_INTERVAL` with the latest geometry of the window.
+#[derive(Default)]
+pub struct GeometryEvents {
+	/// When the latest resize or move event was sent.
+	pub last_sent: Option<Instant>,
+	pub resized: bool,
+	pub moved: bool,
+}
+
+impl GeometryEvents {
+	/// Returns when the held back events may be sent, if there are any
+	pub fn deadline(&self) -> Option<Instant> {
+		match self.resized || self.moved {
+			true => self.last_sent.map(|last_sent| last_sent + GEOMETRY_EVENT_INTERVAL),
+			false => None,
+		}
+	}
+}
+
+/// How long an `eval` command may run before failing, unless it sets its own timeout.
+pub const EVAL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A script run by an `eval` command, waiting for the page to send back its result.
+pub struct PendingEval {
+	pub request_id: Option<Value>,
+	pub deadline: Instant,
+}
+
+static NEXT_DOWNLOAD_ID: AtomicU64 = AtomicU64::new(1);
+
+/// A download started by the page of a window.
+pub struct Download {
+	pub id: u64,
+	pub uri: String,
+	/// Set when a download saved by the webview is cancelled, its file is deleted
+	/// once the webview is done with it.
+	pub cancelled: bool,
+	/// The blob being reassembled, for blob downloads the page sends over IPC.
+	pub blob: Option<BlobDownload>,
+}
+
+impl Download {
+	/// Returns an id that was never used by another download
+	pub fn next_id() -> u64 {
+		NEXT_DOWNLOAD_ID.fetch_add(1, Ordering::Relaxed)
+	}
+}
+
+/// A blob download being reassembled from the chunks sent by the page.
+pub struct BlobDownload {
+	/// Where the blob is saved once every chunk was received.
+	pub path: PathBuf,
+	pub data: Vec<u8>,
+	/// Size of the blob, once the page sent it.
+	pub total: Option<u64>,
+	/// Whether the window closes once the blob is saved.
+	pub is_export: bool,
+	pub policy: ConflictPolicy,
+}
+
+pub enum RenderStatus {
+	/// The renderer page is (re)loading and can't take jobs yet.
+	Loading,
+	Idle,
+	/// Rendering a job that fails with a timeout once the deadline passes.
+	Busy(Instant),
+}
+
+/// A headless export waiting in the queue for an idle renderer.
+pub struct RenderJob {
+	pub showable: ShowableHeadless,
+	pub request_id: Option<Value>,
+	/// When the job fails if no renderer took it yet.
+	pub deadline: Instant,
+}
+
+/// Headless exports waiting for an idle renderer.
+pub struct RenderQueue {
+	pub jobs: VecDeque<RenderJob>,
+	/// How long a job may render before failing, unless it sets its own timeout.
+	pub timeout: Duration,
+}
+
+impl Default for RenderQueue {
+	fn default() -> Self {
+		Self { jobs: VecDeque::new(), timeout: Duration::from_secs(30) }
+	}
+}
+
+impl RenderQueue {
+	pub fn new(timeout: Duration) -> Self {
+		Self { jobs: VecDeque::new(), timeout }
+	}
+
+	/// Queues an export, failing it if it waits longer than it may render
+	pub fn push(&mut self, showable: ShowableHeadless, request_id: Option<Value>) {
+		let deadline = Instant::now() + showable.timeout.unwrap_or(self.timeout);
+		self.jobs.push_back(RenderJob { showable, request_id, deadline });
+	}
+
+	/// Returns the earliest time a queued job times out, if any
+	pub fn deadline(&self) -> Option<Instant> {
+		self.jobs.iter().map(|job| job.deadline).min()
+	}
 }
 
 pub struct WebViewOptions {
@@ -110,12 +336,21 @@ pub struct WebViewOptions {
 	pub url: String,
 	/// JavaScript code to be injected when the webview is ready.
 	pub init_script: Option<String>,
+	/// Plotly events of the figures in the page forwarded to the client.
+	pub plotly_events: Vec<PlotlyEventName>,
+	/// Lowest level of the console messages of the page forwarded to the client, if any.
+	pub forward_console: Option<ConsoleLevel>,
 }
 
 impl Default for WebViewOptions {
 	/// Returns a default set of WebViewOptions.
 	fn default() -> Self {
-		Self { url: "wry://localhost".to_string(), init_script: None }
+		Self {
+			url: "wry://localhost".to_string(),
+			init_script: None,
+			plotly_events: Vec::new(),
+			forward_console: None,
+		}
 	}
 }
 
@@ -135,6 +370,72 @@ impl WebViewOptions {
 		self.init_script = Some(init_script);
 		self
 	}
+
+	/// Sets the Plotly events of the figures in the page forwarded to the client.
+	pub fn with_plotly_events(mut self, plotly_events: Vec<PlotlyEventName>) -> Self {
+		self.plotly_events = plotly_events;
+		self
+	}
+
+	/// Forwards the console messages of the page from `level` up, and uncaught errors, to the client.
+	pub fn with_forward_console(mut self, level: ConsoleLevel) -> Self {
+		self.forward_console = Some(level);
+		self
+	}
+}
+
+pub struct HeadlessOptions {
+	/// Number of headless webviews rendering exports at the same time.
+	pub renderers: usize,
+	/// Plotly.js bundle served to the headless webviews.
+	pub plotly_js: Cow<'static, [u8]>,
+	/// How long an export may render before failing with a timeout.
+	pub render_timeout: Duration,
+}
+
+impl Default for HeadlessOptions {
+	/// Returns a single renderer using the Plotly.js bundle embedded in the binary.
+	fn default() -> Self {
+		Self {
+			renderers: 1,
+			plotly_js: Cow::Borrowed(PLOTLY_JS),
+			render_timeout: RenderQueue::default().timeout,
+		}
+	}
+}
+
+impl HeadlessOptions {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Sets the number of headless webviews rendering exports at the same time.
+	pub fn with_renderers(mut self, renderers: usize) -> Self {
+		self.renderers = renderers;
+		self
+	}
+
+	/// Sets how long an export may render before failing with a timeout.
+	pub fn with_render_timeout(mut self, render_timeout: Duration) -> Self {
+		self.render_timeout = render_timeout;
+		self
+	}
+
+	/// Replaces the embedded Plotly.js bundle with a local file.
+	pub fn with_plotly_js(mut self, path: &str) -> Result<Self, String> {
+		match read(path) {
+			Err(error) => Err(format!("Error reading Plotly.js bundle {}: {}", path, error)),
+			Ok(bytes) => {
+				self.plotly_js = Cow::Owned(bytes);
+				Ok(self)
+			}
+		}
+	}
+
+	/// Returns the version of the Plotly.js bundle, so exports can be reproduced.
+	pub fn plotly_version(&self) -> String {
+		get_plotly_version(&self.plotly_js).unwrap_or_else(|| "unknown".to_string())
+	}
 }
 
 pub struct Showable {
@@ -146,183 +447,254 @@ pub struct Showable {
 	pub data: Option<Value>,
 	pub download_path: String,
 	pub export_image: String,
+	pub download_conflict: ConflictPolicy,
+	pub export_to: ExportTarget,
+	pub label: Option<String>,
+	/// Where the custom protocols of the window serve requests from: the `mounts`
+	/// of the request, then its archive and `assets`, then its asset roots.
+	pub mounts: Vec<Mount>,
+	/// Whether paths without an extension that nothing serves get the html of the
+	/// window instead of a 404, for the client-side routing of archives.
+	pub index_fallback: bool,
+	pub navigation: Rc<NavigationPolicy>,
 	pub theme: Theme,
 	pub options: WebViewOptions,
 }
 
 impl Showable {
-	pub fn new(raw_json: &str) -> Option<Self> {
-		match Self::from_json(raw_json) {
-			Some(item) => Some(item),
-			None => Some(Self::default()),
-		}
+	/// Parses a message from stdin into the window to show
+	/// # Arguments
+	/// * `raw_json` - The message as read from stdin
+	/// # Returns
+	/// * `Result<Showable, String>` - The window to show or why the message is invalid
+	pub fn new(raw_json: &str) -> Result<Self, String> {
+		Self::from_request(ShowRequest::from_json(raw_json)?)
 	}
 
-	pub fn from_json(raw_json: &str) -> Option<Self> {
-		let json: serde_json::Value = match serde_json::from_str(raw_json) {
-			Err(_) => return None,
-			Ok(item) => item,
-		};
-		let content: String = match canonicalize(&json["html"].as_str().unwrap_or_default())
-		{
-			Err(_) => json["html"]
-				.as_str()
-				.unwrap_or(Showable::default().content.as_str())
-				.to_string(),
-			Ok(path) => match read_to_string(&path) {
-				Err(_) => return None,
-				Ok(item) => item,
-			},
-		};
-
-		let json_data: Value = json["json_data"].clone();
-		let icon = json["icon"].as_str().unwrap_or_default().to_string();
-		let title = json["title"].as_str().unwrap_or_default().to_string();
-		let mut height: Option<u32> =
-			json["height"].as_u64().and_then(|x| u32::try_from(x).ok());
-		let mut width: Option<u32> =
-			json["width"].as_u64().and_then(|x| u32::try_from(x).ok());
-		let mut theme = Theme::Light;
+	pub fn from_request(mut request: ShowRequest) -> Result<Self, String> {
+		let (json_data, json_options) =
+			request.json_data.take().map(JsonData::into_parts).unwrap_or_default();
 		let mut options = WebViewOptions::new();
+		if let Some(url) = json_options.url {
+			options = options.with_url(url);
+		}
+		if request.proxy.is_some() {
+			options = options.with_url("wry://localhost/".to_string());
+		}
+		if let Some(init_script) = json_options.init_script {
+			options = options.with_init_script(init_script);
+		}
+		if let Some(plotly_events) = json_options.plotly_events {
+			options = options.with_plotly_events(plotly_events);
+		}
+		if let Some(level) = json_options.forward_console {
+			options = options.with_forward_console(level);
+		}
 
-		if !json_data.is_null() {
-			theme = match json_data["theme"].as_str().unwrap_or_default() {
-				"dark" => Theme::Dark,
-				"light" => Theme::Light,
-				_ => theme,
-			};
-			if json_data["layout"].is_object() {
-				let raw_width = json_data["layout"]["width"].as_u64().unwrap_or(800);
-				let raw_height = json_data["layout"]["height"].as_u64().unwrap_or(600);
-				width = Some(u32::try_from(raw_width).unwrap_or(800));
-				height = Some(u32::try_from(raw_height).unwrap_or(600));
+		// Without declared roots only the directory of an html file is served, so html
+		// strings and proxied servers can't read the working directory
+		let asset_roots = match (request.asset_roots, &request.html) {
+			(Some(roots), _) => roots,
+			(None, Some(html)) if is_html_path(html) => {
+				let directory = Path::new(html).parent().unwrap_or(Path::new(""));
+				vec![directory.to_string_lossy().to_string()]
 			}
-			if json_data["url"].is_string() {
-				options = options.with_url(json_data["url"].as_str().unwrap().to_string());
+			(None, _) => Vec::new(),
+		};
+		let asset_roots = asset_roots
+			.iter()
+			.map(|root| {
+				let directory = if root.is_empty() { "." } else { root };
+				canonicalize(directory)
+					.map_err(|error| format!("Invalid asset root {:?}: {}", root, error))
+			})
+			.collect::<Result<Vec<_>, _>>()?;
+
+		let mut assets: MemoryFiles = match &request.archive {
+			Some(archive) => read_archive(archive)?
+				.into_iter()
+				.map(|(path, data)| (path, MemoryAsset::new(data)))
+				.collect(),
+			None => HashMap::new(),
+		};
+		assets.extend(decode_assets(request.assets.unwrap_or_default())?);
+
+		let content: String = match request.html {
+			Some(html) => read_html(html)?,
+			None if request.proxy.is_some() => "".to_string(),
+			None if options.url != WebViewOptions::default().url => "".to_string(),
+			None if request.archive.is_some() => match assets.get("index.html") {
+				Some(index) => String::from_utf8_lossy(index.bytes()).to_string(),
+				None => return Err("Missing html, the archive has no index.html".to_string()),
+			},
+			None => {
+				return Err(
+					"Missing html, expected an html string or path to an html file".to_string(),
+				);
 			}
-			if json_data["init_script"].is_string() {
-				options = options
-					.with_init_script(json_data["init_script"].as_str().unwrap().to_string());
+		};
+
+		let mut mounts = request
+			.mounts
+			.unwrap_or_default()
+			.into_iter()
+			.map(Mount::from_request)
+			.collect::<Result<Vec<_>, _>>()?;
+		mounts.push(Mount::new("wry", "/", MountSource::Memory(assets)));
+		if !asset_roots.is_empty() {
+			mounts.push(Mount::new("wry", "/", MountSource::Directory(asset_roots)));
+		}
+		if let Some(proxy) = request.proxy {
+			let mut target = ProxyTarget::new(&proxy.url)?
+				.with_request_headers(proxy.request_headers.unwrap_or_default())
+				.with_response_headers(proxy.response_headers.unwrap_or_default());
+			if proxy.inject_scripts.unwrap_or(true) {
+				target = target.with_script(PROXY_INJECT_JS.to_string());
 			}
+			mounts.push(Mount::new("wry", "/", MountSource::Proxy(target)));
+		}
+
+		let icon = request.icon.unwrap_or_default();
+		if !icon.is_empty() && canonicalize(&icon).is_err() {
+			return Err(format!("icon file not found: {}", icon));
+		}
+
+		let mut height = request.height;
+		let mut width = request.width;
+		if let Some(layout) = json_options.layout {
+			width = Some(layout.width.map_or(800, |width| width.round() as u32));
+			height = Some(layout.height.map_or(600, |height| height.round() as u32));
 		}
 
-		let export_image = json["export_image"].as_str().unwrap_or_default().to_string();
-		let download_path = json["download_path"].as_str().unwrap_or_default().to_string();
+		let navigation = request
+			.navigation
+			.map_or_else(NavigationPolicy::default, NavigationPolicy::from_request)
+			.with_schemes(mount_schemes(&mounts));
 
-		Some(Self {
+		Ok(Self {
 			content,
-			title,
+			title: request.title.unwrap_or_default(),
 			height,
 			width,
 			icon,
 			data: Some(json_data),
-			download_path,
-			export_image,
-			theme,
+			download_path: request.download_path.unwrap_or_default(),
+			export_image: request.export_image.unwrap_or_default(),
+			download_conflict: request.download_conflict.unwrap_or_default(),
+			export_to: request.export_to.unwrap_or_default(),
+			label: request.label,
+			mounts,
+			index_fallback: request.archive.is_some(),
+			navigation: Rc::new(navigation),
+			theme: match json_options.theme.as_deref() {
+				Some("dark") => Theme::Dark,
+				_ => Theme::Light,
+			},
 			options,
 		})
 	}
 }
 
-impl Default for Showable {
-	fn default() -> Self {
-		Self {
-			content: "
-			<h1 style='color:red'>No html content to show,
-			please provide a valid html string or path to html file.</h1>
-			"
-			.to_string(),
-			title: "Error Creating Showable Object".to_string(),
-			height: None,
-			width: None,
-			icon: "".to_string(),
-			data: None,
-			download_path: "".to_string(),
-			export_image: "".to_string(),
-			theme: Theme::Light,
-			options: WebViewOptions::default(),
-		}
+/// Whether a string meant as html content looks like the path to an html file instead
+fn is_html_path(html: &str) -> bool {
+	let extension = Path::new(html).extension().and_then(|ext| ext.to_str());
+	!html.contains('<') && matches!(extension, Some("html" | "htm"))
+}
+
+/// Returns the content of `html` if it is the path to an html file, or `html` itself otherwise
+/// # Arguments
+/// * `html` - The `html` key of a message, an html string or path to an html file
+/// # Returns
+/// * `Result<String, String>` - The html content or why the file can't be read
+pub fn read_html(html: String) -> Result<String, String> {
+	match canonicalize(&html) {
+		Ok(path) => read_to_string(&path)
+			.map_err(|error| format!("Error reading html file {:?}: {}", path, error)),
+		Err(_) if is_html_path(&html) => Err(format!("html file not found: {}", html)),
+		Err(_) => Ok(html),
 	}
 }
 
 pub struct ShowableHeadless {
 	pub data: Option<Value>,
 	pub export_image: String,
-	pub scale: Option<u32>,
+	pub scale: Option<f64>,
+	pub format: ImageFormat,
+	pub width: Option<f64>,
+	pub height: Option<f64>,
+	/// Overrides the render timeout for this export.
+	pub timeout: Option<Duration>,
 }
 
 impl ShowableHeadless {
-	pub fn new(raw_json: &str) -> Option<Self> {
-		let json: serde_json::Value = match serde_json::from_str(raw_json) {
-			Err(_) => return None,
-			Ok(item) => item,
-		};
-
-		let json_data: Value = json["json_data"].clone();
-		let export_image = json["export_image"].as_str().unwrap_or_default().to_string();
-		let mut data: Option<Value> = None;
-		let mut scale: Option<u32> = None;
-
-		if !json_data.is_null() {
-			if json_data["layout"].is_object() {
-				data = Some(json_data);
-				scale =
-					Some(json["json_data"]["scale"].as_u64().unwrap_or(2).try_into().unwrap());
+	/// Parses a message from stdin into the figure to export
+	/// # Arguments
+	/// * `raw_json` - The message as read from stdin
+	/// # Returns
+	/// * `Result<ShowableHeadless, String>` - The figure to export or why the message is invalid
+	pub fn new(raw_json: &str) -> Result<Self, String> {
+		let request = ShowRequest::from_json(raw_json)?;
+
+		let (data, options) = match request.json_data {
+			Some(json_data @ JsonData::Object(_)) => json_data.into_parts(),
+			_ => {
+				return Err("Missing json_data, expected a Plotly figure object".to_string());
 			}
-		}
-
-		Some(Self { data, export_image, scale })
+		};
+		let layout = options.layout.unwrap_or_default();
+
+		Ok(Self {
+			data: Some(data),
+			export_image: request.export_image.unwrap_or_default(),
+			scale: Some(options.scale.unwrap_or(2.0)),
+			format: options.format.unwrap_or_default(),
+			width: Some(layout.width.unwrap_or(800.0)),
+			height: Some(layout.height.unwrap_or(600.0)),
+			timeout: request.timeout.map(Duration::from_secs_f64),
+		})
 	}
 }
 
 impl Default for ShowableHeadless {
 	fn default() -> Self {
-		Self { data: None, export_image: "".to_string(), scale: None }
+		Self {
+			data: None,
+			export_image: "".to_string(),
+			scale: None,
+			format: ImageFormat::default(),
+			width: None,
+			height: None,
+			timeout: None,
+		}
 	}
 }
 
 pub struct PlotData {
 	pub figure: Option<Value>,
 	pub format: String,
-	pub width: Option<u32>,
-	pub height: Option<u32>,
-	pub scale: Option<u32>,
+	pub width: Option<f64>,
+	pub height: Option<f64>,
+	pub scale: Option<f64>,
 }
 
 impl PlotData {
 	pub fn new(showable: &ShowableHeadless) -> Self {
-		let figure = showable.data.clone();
-		let mut format = "png".to_string();
-		let mut width = None;
-		let mut height = None;
-		let scale = showable.scale;
-
-		if !figure.is_none() {
-			let raw_width =
-				figure.as_ref().unwrap()["layout"]["width"].as_u64().unwrap_or(800);
-			let raw_height =
-				figure.as_ref().unwrap()["layout"]["height"].as_u64().unwrap_or(600);
-			width = Some(u32::try_from(raw_width).unwrap_or(800));
-			height = Some(u32::try_from(raw_height).unwrap_or(600));
-			format = figure.as_ref().unwrap()["format"].as_str().unwrap_or("png").to_string();
+		Self {
+			figure: showable.data.clone(),
+			format: showable.format.as_str().to_string(),
+			width: showable.width,
+			height: showable.height,
+			scale: showable.scale,
 		}
-
-		Self { figure, format, width, height, scale }
 	}
 
-	pub fn to_json(raw_json: &str) -> Value {
-		let show = match ShowableHeadless::new(raw_json) {
-			Some(showable) => showable,
-			None => ShowableHeadless::default(),
-		};
-
-		let plot_data = Self::new(&show);
+	pub fn to_json(&self) -> Value {
 		serde_json::json!({
-				"figure": plot_data.figure,
-				"format": plot_data.format,
-				"width": plot_data.width,
-				"height": plot_data.height,
-				"scale": plot_data.scale,
+				"figure": self.figure,
+				"format": self.format,
+				"width": self.width,
+				"height": self.height,
+				"scale": self.scale,
 		})
 	}
 }
@@ -1,27 +1,184 @@
 use image::ImageFormat;
-use std::{fs::read, path::PathBuf};
+use sha2::{Digest, Sha256};
+use std::{
+	env,
+	fs::{File, canonicalize, read},
+	io,
+	path::{Component, Path, PathBuf},
+};
 
-use wry::application::window::Icon;
+use crate::protocol::ConflictPolicy;
+
+use wry::{application::window::Icon, http::StatusCode};
+
+#[cfg(target_os = "windows")]
+use simple_home_dir::home_dir;
+#[cfg(target_os = "windows")]
+use wry::webview::WebContext;
 
 use urlencoding::decode as urldecode;
 
+/// Returns the path of a download reported by the webview, which may be a file url
+/// # Arguments
+/// * `path` - A path, or a percent-encoded file url like `file:///C:/Users/...`
+/// # Returns
+/// * `PathBuf` - The path, unchanged unless it was a file url
 pub fn decode_path(path: &str) -> PathBuf {
-	let decoded = urldecode(&path).expect("UTF-8").to_string();
-	let file_path = match decoded.starts_with("file://") {
-		true => {
-			let decoded = urldecode(&path).expect("UTF-8").to_string();
-			let path = PathBuf::from(&decoded);
-			if ":" == &decoded[9..10] {
-				path.strip_prefix("file://").unwrap().to_path_buf()
-			} else {
-				let path = PathBuf::from(&decoded[6..]);
-				path.to_path_buf()
-			}
+	let url = match path.strip_prefix("file://") {
+		Some(url) => url,
+		None => return PathBuf::from(path),
+	};
+	let decoded = match urldecode(url) {
+		Ok(decoded) => decoded.to_string(),
+		Err(_) => return PathBuf::from(path),
+	};
+	// `file:///C:/...` holds a Windows path after its third `/`
+	match decoded.get(2..3) {
+		Some(":") => PathBuf::from(&decoded[1..]),
+		_ => PathBuf::from(decoded),
+	}
+}
+
+/// Resolves the path of a `wry://` request to a file inside the asset roots of a window
+/// # Arguments
+/// * `request_path` - The path of the request, without its leading `/`
+/// * `roots` - The canonical directories the window may load files from
+/// # Returns
+/// * `Result<PathBuf, StatusCode>` - The file to serve, or `FORBIDDEN` when the path
+/// points outside the roots, `BAD_REQUEST` when it isn't valid percent-encoded UTF-8
+/// and `NOT_FOUND` when no root has it
+pub fn resolve_asset(
+	request_path: &str, roots: &[PathBuf],
+) -> Result<PathBuf, StatusCode> {
+	let decoded = match urldecode(request_path) {
+		Ok(decoded) => PathBuf::from(decoded.as_ref()),
+		Err(_) => return Err(StatusCode::BAD_REQUEST),
+	};
+	let escapes = decoded.is_absolute()
+		|| decoded.components().any(|component| component == Component::ParentDir);
+	let mut status = match escapes {
+		true => StatusCode::FORBIDDEN,
+		false => StatusCode::NOT_FOUND,
+	};
+	for root in roots {
+		// Canonical paths have no `..` or symlinks left, so they can't leave the root unnoticed
+		match canonicalize(root.join(&decoded)) {
+			Ok(path) if path.starts_with(root) => return Ok(path),
+			Ok(_) => status = StatusCode::FORBIDDEN,
+			Err(_) => {}
 		}
-		false => PathBuf::from(path),
+	}
+	Err(status)
+}
+
+/// Returns where a download is saved, `export_image` taking precedence over `download_path`
+/// # Arguments
+/// * `default_path` - The path the webview would save the download to
+/// * `download_path` - The download directory of the window, if any
+/// * `export_image` - The path the window exports its image to, if any
+/// # Returns
+/// * `PathBuf` - The path to save the download to
+pub fn download_target(
+	default_path: &Path, download_path: &str, export_image: &str,
+) -> PathBuf {
+	let file_name = default_path.file_name().unwrap_or_default();
+	let directory = match (export_image.is_empty(), download_path.is_empty()) {
+		(false, _) => PathBuf::from(export_image),
+		(true, false) => PathBuf::from(download_path),
+		(true, true) => return default_path.to_path_buf(),
 	};
+	match directory.is_dir() || export_image.is_empty() {
+		true => directory.join(file_name),
+		false => directory,
+	}
+}
+
+/// Applies a conflict policy to the path a download is about to be saved to
+/// # Arguments
+/// * `path` - Where the download would be saved
+/// * `policy` - What to do if a file already exists at `path`
+/// # Returns
+/// * `Result<PathBuf, String>` - Where to save the download or why it can't be saved
+pub fn resolve_conflict(
+	path: PathBuf, policy: ConflictPolicy,
+) -> Result<PathBuf, String> {
+	if !path.exists() {
+		return Ok(path);
+	}
+	match policy {
+		ConflictPolicy::Overwrite => Ok(path),
+		ConflictPolicy::Fail => Err(format!("File already exists: {:?}", path)),
+		ConflictPolicy::Rename => {
+			let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+			let extension = match path.extension() {
+				Some(extension) => format!(".{}", extension.to_string_lossy()),
+				None => "".to_string(),
+			};
+			let mut suffix = 1;
+			loop {
+				let renamed =
+					path.with_file_name(format!("{} ({}){}", stem, suffix, extension));
+				if !renamed.exists() {
+					return Ok(renamed);
+				}
+				suffix += 1;
+			}
+		}
+	}
+}
+
+/// Returns the temporary file a download of a window exporting to memory is saved to,
+/// so the webview never writes it to a directory the user sees
+/// # Arguments
+/// * `download` - The id of the download
+/// * `target` - Where the download would be saved otherwise
+/// # Returns
+/// * `PathBuf` - The temporary file to save the download to
+pub fn export_temp_path(download: u64, target: &Path) -> PathBuf {
+	let file_name = target.file_name().unwrap_or_default().to_string_lossy();
+	env::temp_dir().join(format!("pywry-{}-{}", download, file_name))
+}
+
+/// Returns the name of an exported file, without the prefix of `export_temp_path`
+pub fn export_file_name(download: u64, path: &Path) -> String {
+	let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+	let prefix = format!("pywry-{}-", download);
+	file_name.strip_prefix(&prefix).unwrap_or(&file_name).to_string()
+}
+
+/// Returns the size in bytes and hex encoded SHA-256 of a file
+pub fn file_digest(path: &Path) -> io::Result<(u64, String)> {
+	let mut file = File::open(path)?;
+	let mut hasher = Sha256::new();
+	let size = io::copy(&mut file, &mut hasher)?;
+	Ok((size, format!("{:x}", hasher.finalize())))
+}
 
-	file_path
+/// Returns the web context of the windows, caching their data in the home directory
+/// instead of next to the executable
+#[cfg(target_os = "windows")]
+pub fn web_context() -> WebContext {
+	let home = home_dir().unwrap_or_else(env::temp_dir);
+	WebContext::new(Some(home.join(".cache").join("wry")))
+}
+
+/// Reads the Plotly.js version from the license header of a bundle
+/// # Arguments
+/// * `bundle` - The contents of the Plotly.js bundle
+/// # Returns
+/// * `Option<String>` - The version, e.g. `2.21.0`, or None
+pub fn get_plotly_version(bundle: &[u8]) -> Option<String> {
+	let header = String::from_utf8_lossy(&bundle[..bundle.len().min(512)]);
+	let start = header.find("plotly.js v")? + "plotly.js v".len();
+	let version: String = header[start..]
+		.chars()
+		.take_while(|c| c.is_ascii_alphanumeric() || *c == '.' || *c == '-')
+		.collect();
+
+	match version.is_empty() {
+		true => None,
+		false => Some(version),
+	}
 }
 
 /// Gets the icon from the path
@@ -51,3 +208,93 @@ pub fn get_icon(icon: &str) -> Option<Icon> {
 	};
 	icon_object
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::fs::{create_dir_all, remove_dir_all, write};
+
+	/// A root directory with `a.txt`, `my file.txt`, `é.txt` and `sub/b.txt`, next to
+	/// an `outside` directory holding `secret.txt`
+	fn asset_tree(name: &str) -> (PathBuf, PathBuf) {
+		let base = env::temp_dir().join(format!("pywry-{}-{}", name, std::process::id()));
+		remove_dir_all(&base).unwrap_or_default();
+		let (root, outside) = (base.join("root"), base.join("outside"));
+		create_dir_all(root.join("sub")).unwrap();
+		create_dir_all(&outside).unwrap();
+		for file in ["a.txt", "my file.txt", "é.txt", "sub/b.txt"] {
+			write(root.join(file), file).unwrap();
+		}
+		write(outside.join("secret.txt"), "secret").unwrap();
+		(canonicalize(root).unwrap(), canonicalize(outside).unwrap())
+	}
+
+	#[test]
+	fn resolve_asset_serves_files_of_the_roots() {
+		let (root, _) = asset_tree("files");
+		let roots = [root.clone()];
+		assert_eq!(resolve_asset("a.txt", &roots), Ok(root.join("a.txt")));
+		assert_eq!(resolve_asset("sub/b.txt", &roots), Ok(root.join("sub/b.txt")));
+		assert_eq!(resolve_asset("sub/../a.txt", &roots), Ok(root.join("a.txt")));
+		assert_eq!(resolve_asset("missing.txt", &roots), Err(StatusCode::NOT_FOUND));
+	}
+
+	#[test]
+	fn resolve_asset_decodes_file_names() {
+		let (root, _) = asset_tree("encoded");
+		let roots = [root.clone()];
+		assert_eq!(resolve_asset("my%20file.txt", &roots), Ok(root.join("my file.txt")));
+		assert_eq!(resolve_asset("%C3%A9.txt", &roots), Ok(root.join("é.txt")));
+		assert_eq!(resolve_asset("%FF", &roots), Err(StatusCode::BAD_REQUEST));
+	}
+
+	#[test]
+	fn resolve_asset_refuses_paths_leaving_the_roots() {
+		let (root, outside) = asset_tree("escapes");
+		let roots = [root];
+		let secret = outside.join("secret.txt");
+		assert_eq!(
+			resolve_asset("../outside/secret.txt", &roots),
+			Err(StatusCode::FORBIDDEN)
+		);
+		assert_eq!(
+			resolve_asset("%2e%2e/outside/secret.txt", &roots),
+			Err(StatusCode::FORBIDDEN)
+		);
+		assert_eq!(
+			resolve_asset("sub/../../outside/secret.txt", &roots),
+			Err(StatusCode::FORBIDDEN)
+		);
+		let absolute = urlencoding::encode(&secret.to_string_lossy()).to_string();
+		assert_eq!(resolve_asset(&absolute, &roots), Err(StatusCode::FORBIDDEN));
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn resolve_asset_refuses_symlinks_leaving_the_roots() {
+		let (root, outside) = asset_tree("symlinks");
+		std::os::unix::fs::symlink(outside.join("secret.txt"), root.join("link.txt"))
+			.unwrap();
+		std::os::unix::fs::symlink(&outside, root.join("linked")).unwrap();
+		let roots = [root];
+		assert_eq!(resolve_asset("link.txt", &roots), Err(StatusCode::FORBIDDEN));
+		assert_eq!(resolve_asset("linked/secret.txt", &roots), Err(StatusCode::FORBIDDEN));
+	}
+
+	#[test]
+	fn decode_path_decodes_file_urls_only() {
+		assert_eq!(
+			decode_path("file:///home/me/a%20b.png"),
+			PathBuf::from("/home/me/a b.png")
+		);
+		assert_eq!(
+			decode_path("file:///C:/Users/a%20b.png"),
+			PathBuf::from("C:/Users/a b.png")
+		);
+		assert_eq!(
+			decode_path("/home/me/100%25.png"),
+			PathBuf::from("/home/me/100%25.png")
+		);
+		assert_eq!(decode_path("file:///%FF"), PathBuf::from("file:///%FF"));
+	}
+}
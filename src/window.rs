@@ -1,22 +1,19 @@
 use crate::{
-	constants::DEV_TOOLS_HTML,
-	events::handle_events,
+	constants::{DEV_TOOLS_HTML, FORWARD_CONSOLE_JS, PLOTLY_EVENTS_JS},
+	events::{handle_events, next_deadline},
 	handlers::add_handlers,
 	pipe::run_listener,
-	structs::{ConsolePrinter, Showable, UserEvent},
-	utils::{decode_path, get_icon},
+	relay::Relay,
+	router::Router,
+	structs::{ConsolePrinter, RenderQueue, Showable, UserEvent},
+	utils::get_icon,
 };
-use mime_guess;
 
 #[cfg(target_os = "windows")]
-use simple_home_dir::*;
-#[cfg(target_os = "windows")]
-use std::{env::temp_dir, path::PathBuf};
+use crate::utils::web_context;
+use serde_json::json;
 
-use std::{
-	collections::HashMap,
-	fs::{canonicalize, read},
-};
+use std::{collections::HashMap, sync::Arc};
 
 use wry::{
 	application::{
@@ -24,16 +21,12 @@ use wry::{
 		event_loop::{ControlFlow, EventLoop, EventLoopProxy, EventLoopWindowTarget},
 		window::{Theme, WindowBuilder, WindowId},
 	},
-	http::{header::CONTENT_TYPE, Response},
 	webview::{WebView, WebViewBuilder},
 };
 
 #[cfg(wry_event_loop)]
 use wry::application::event_loop::EventLoopBuilder;
 
-#[cfg(target_os = "windows")]
-use wry::webview::WebContext;
-
 /// Creates a new window and returns the window id and webview
 /// # Arguments
 /// * `to_show` - The Showable struct that contains the information to show
@@ -41,11 +34,12 @@ use wry::webview::WebContext;
 /// * `proxy` - The event loop proxy to send events to
 /// * `console` - The ConsolePrinter struct to print log messages to the console
 /// # Returns
-/// * `Result<(WindowId, WebView), String>` - The window id and webview or an error message
+/// * `Result<(WindowId, WebView, Option<Relay>), String>` - The window id, webview and
+///   the relay its pages are loaded from when it proxies a server, or an error message
 pub fn create_new_window(
 	to_show: Showable, event_loop: &&EventLoopWindowTarget<UserEvent>,
 	proxy: &EventLoopProxy<UserEvent>, console: ConsolePrinter,
-) -> Result<(WindowId, WebView), String> {
+) -> Result<(WindowId, WebView, Option<Relay>), String> {
 	let content = to_show.content.clone().into_bytes();
 	let window_icon = to_show.icon.clone();
 
@@ -110,49 +104,49 @@ pub fn create_new_window(
 		webview.with_background_color(background_color).with_hotkeys_zoom(true);
 
 	#[cfg(target_os = "windows")]
-	let mut cache_directory = WebContext::new(Some(PathBuf::from(
-		home_dir().unwrap_or_else(|| temp_dir()).join(".cache").join("wry"),
-	)));
+	let mut cache_directory = web_context();
 	#[cfg(target_os = "windows")]
 	let protocol = protocol.with_web_context(&mut cache_directory);
 
-	let protocol = match to_show.options.url.starts_with("wry://") {
-		true => protocol.with_custom_protocol("wry".into(), move |request| {
-			let path = request.uri().path();
-			let clean_path = &path[1..];
-			let content = content.clone();
-			let mut mime = mime_guess::from_path("index.html");
-
-			let content = if path == "/" {
-				content.into()
-			} else {
-				let decoded = decode_path(clean_path);
-				mime = mime_guess::from_path(decoded.clone());
-				match read(canonicalize(decoded).unwrap_or_default()) {
-					Err(_) => content.into(),
-					Ok(bytes) => bytes.into(),
-				}
-			};
-
-			let mimetype = mime
-				.first()
-				.map(|mime| mime.to_string())
-				.unwrap_or_else(|| "text/plain".to_string());
-
-			#[cfg(target_os = "windows")]
-			let headers = "https://wry.localhost".to_string();
-			#[cfg(not(target_os = "windows"))]
-			let headers = "wry://localhost".to_string();
-
-			Response::builder()
-				.header(CONTENT_TYPE, mimetype)
-				.header("Access-Control-Allow-Origin", headers)
-				.header("Accept-Encoding", "gzip, compress, br, deflate")
-				.body(content)
-				.map_err(Into::into)
-		}),
-		false => protocol,
+	let asset_proxy = proxy.clone();
+	let router = Arc::new(
+		Router::new(to_show.mounts, content)
+			.with_index_fallback(to_show.index_fallback)
+			.with_denied_handler(move |path| {
+				console.error(&format!("Blocked request outside the asset roots: {}", path));
+				asset_proxy
+					.send_event(UserEvent::AssetDenied(path.to_string(), window_id))
+					.unwrap_or_default();
+			}),
+	);
+	// Servers may answer slowly, stream or open WebSockets, which custom protocols can't
+	// wait for without freezing every window, so windows proxying one load from a relay
+	let relay = match router.proxies() && to_show.options.url.starts_with("wry://") {
+		true => Some(Relay::start(router.clone())?),
+		false => None,
 	};
+	let url = match &relay {
+		Some(relay) => {
+			to_show.navigation.add_origin(&relay.origin());
+			relay.url(&to_show.options.url)
+		}
+		None => to_show.options.url.clone(),
+	};
+
+	let mut protocol = protocol;
+	for scheme in router.schemes() {
+		// Pages loaded from a url don't get to read local files through `wry://`
+		if scheme == "wry" && !url.starts_with("wry://") {
+			continue;
+		}
+		let router = router.clone();
+		protocol = protocol.with_custom_protocol(scheme.clone(), move |request| {
+			router.handle(&scheme, request)
+		});
+	}
+
+	// The page the window opens with is loaded whatever its policy says
+	to_show.navigation.approve(&url);
 
 	let export_image = to_show.export_image.clone();
 	let _is_export = !export_image.is_empty();
@@ -184,17 +178,38 @@ pub fn create_new_window(
 		window_id,
 		download_path,
 		export_image,
+		to_show.download_conflict,
+		to_show.export_to,
+		to_show.navigation.clone(),
 		&window_icon,
 		Some(false),
 		console,
 	);
 
+	let init_view = match to_show.options.plotly_events.is_empty() {
+		true => init_view,
+		false => init_view.with_initialization_script(&format!(
+			"window.pywry_plotly_events = {};\n{}",
+			json!(to_show.options.plotly_events),
+			PLOTLY_EVENTS_JS
+		)),
+	};
+
+	let init_view = match to_show.options.forward_console {
+		None => init_view,
+		Some(level) => init_view.with_initialization_script(&format!(
+			"window.pywry_console_level = {};\n{}",
+			json!(level),
+			FORWARD_CONSOLE_JS
+		)),
+	};
+
 	let init_view = match to_show.options.init_script.is_some() {
 		true => init_view.with_initialization_script(&to_show.options.init_script.unwrap()),
 		false => init_view,
 	};
 
-	return match init_view.with_devtools(console.active).with_url(&to_show.options.url) {
+	return match init_view.with_devtools(console.active).with_url(&url) {
 		Err(error3) => return Err(error3.to_string()),
 		Ok(subitem) => match subitem.build() {
 			Err(error4) => return Err(error4.to_string()),
@@ -203,7 +218,7 @@ pub fn create_new_window(
 					let proxy = proxy.clone();
 					proxy.send_event(UserEvent::NewWindowCreated(window_id)).unwrap_or_default();
 				}
-				Ok((window_id, sub2item))
+				Ok((window_id, sub2item, relay))
 			}
 		},
 	};
@@ -224,6 +239,7 @@ pub fn start_wry(console: ConsolePrinter) -> Result<(), String> {
 
 	let proxy = event_loop.create_proxy();
 	let mut webviews = HashMap::new();
+	let mut jobs = RenderQueue::default();
 	let mut listener_spawned = false;
 
 	event_loop.run(move |event, event_loop, control_flow| {
@@ -251,6 +267,19 @@ pub fn start_wry(console: ConsolePrinter) -> Result<(), String> {
 			listener_spawned = true;
 		}
 
-		handle_events(event, &mut webviews, &proxy, console.clone(), event_loop, false);
+		handle_events(
+			event,
+			&mut webviews,
+			&mut jobs,
+			&proxy,
+			console.clone(),
+			event_loop,
+			false,
+		);
+
+		*control_flow = match next_deadline(&webviews, &jobs) {
+			Some(deadline) => ControlFlow::WaitUntil(deadline),
+			None => ControlFlow::Wait,
+		};
 	});
 }